@@ -0,0 +1,123 @@
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{future, Future, Stream};
+use hyper::{self, StatusCode};
+use hyper::header::{ContentLength, ContentType};
+use hyper::server::{Http, Request, Response, Service};
+use serde_json::Value;
+use tokio_core::reactor::Handle;
+
+use librespot::core::session::Session;
+
+use remote;
+
+// what Main knows about its well-being - reported on the /health endpoint
+pub struct Health {
+	pub connected: bool,
+	pub reconnects: u32,
+	pub last_event: Option<SystemTime>,
+	// the current session, to ask Spotify what's playing on /state
+	pub session: Option<Session>,
+	started: Instant
+}
+
+impl Health {
+	pub fn new() -> Health {
+		Health {
+			connected: false,
+			reconnects: 0,
+			last_event: None,
+			session: None,
+			started: Instant::now()
+		}
+	}
+
+	fn to_json(&self) -> Value {
+		json!({
+			"connected": self.connected,
+			"reconnects": self.reconnects,
+			"last_event": self.last_event.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|since| since.as_secs()),
+			"uptime": self.started.elapsed().as_secs()
+		})
+	}
+}
+
+// a minimal HTTP server for monitoring tools to poll - and for LMS to catch up on what it might have missed.
+// The ident is what we're querying the Spotify Connect devices' state as, see --get-state.
+pub fn serve(addr: SocketAddr, health: Rc<RefCell<Health>>, ident: String, handle: &Handle) -> io::Result<()> {
+	let connections = handle.clone();
+	let service_handle = handle.clone();
+
+	let server = Http::new()
+		.serve_addr_handle(&addr, handle, move || Ok(HealthService {
+			health: health.clone(),
+			ident: ident.clone(),
+			handle: service_handle.clone()
+		}))
+		.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+	handle.spawn(server
+		.for_each(move |connection| {
+			connections.spawn(connection.map(|_| ()).map_err(|_| ()));
+			Ok(())
+		})
+		.map_err(|_| ()));
+
+	Ok(())
+}
+
+struct HealthService {
+	health: Rc<RefCell<Health>>,
+	ident: String,
+	handle: Handle
+}
+
+fn json_response(status: StatusCode, data: Value) -> Response {
+	let body = data.to_string();
+
+	Response::new()
+		.with_status(status)
+		.with_header(ContentType::json())
+		.with_header(ContentLength(body.len() as u64))
+		.with_body(body)
+}
+
+impl Service for HealthService {
+	type Request = Request;
+	type Response = Response;
+	type Error = hyper::Error;
+	type Future = Box<Future<Item=Response, Error=hyper::Error>>;
+
+	fn call(&self, req: Request) -> Self::Future {
+		let response = match req.path() {
+			"/health" => json_response(StatusCode::Ok, self.health.borrow().to_json()),
+			// the same as --get-state, from the running instance
+			"/state" => {
+				let session = self.health.borrow().session.clone();
+
+				return match session {
+					Some(session) => {
+						let ident = self.ident.clone();
+						let handle = self.handle.clone();
+
+						let state = ::mercury_with_retry("the Spotify Connect devices".to_string(), &self.handle, move || remote::devices(&session, ident.clone(), &handle))
+							.then(|devices| Ok(match devices {
+								Ok(devices) => json_response(StatusCode::Ok, remote::state_json(&devices)),
+								Err(_) => json_response(StatusCode::BadGateway, json!({ "error": "request_failed" }))
+							}));
+
+						Box::new(state)
+					}
+					None => Box::new(future::ok(json_response(StatusCode::ServiceUnavailable, json!({ "error": "not_connected" }))))
+				};
+			}
+			_ => Response::new().with_status(StatusCode::NotFound)
+		};
+
+		Box::new(future::ok(response))
+	}
+}
@@ -2,35 +2,125 @@ extern crate futures;
 extern crate hyper;
 extern crate tokio_core;
 
+use std::cell::{Cell, RefCell};
+use std::io::{self, stderr, Write};
+use std::net::IpAddr;
+use std::process::exit;
+use std::rc::Rc;
 use std::str::FromStr;
-use tokio_core::reactor::{Handle};
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
 
-use futures::Future;
+use futures::{future, Future, Stream};
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use hyper::{Method, Request, Uri, Client};
+use hyper::client::FutureResponse;
 use hyper::header::{Authorization, ContentLength, ContentType};
+use serde_json::{self, Value};
 
+use librespot::core::spotify_id::SpotifyId;
 use librespot::playback::player::PlayerEvent;
 
+use position::Position;
+
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use hyper::client::Service;
+#[cfg(unix)]
+use tokio_uds::UnixStream;
+
 #[derive(Clone)]
 pub struct LMS {
 	base_url: Option<String>,
-	player_mac: Option<String>,
-	auth: Option<String>
+	player_macs: Vec<String>,
+	auth: Option<String>,
+	socket: Option<String>,
+	uri_form: bool,
+	pass_through: bool,
+
+	position: Position,
+
+	// number of notifications sent but not answered yet
+	in_flight: Rc<Cell<usize>>,
+	// only complain once about answers which aren't JSON-RPC
+	warned: Rc<Cell<bool>>,
+	// ...and once about LMS being unreachable, until it answers again
+	unreachable: Rc<Cell<bool>>,
+	// tell whoever's interested about notifications which didn't make it
+	failures: Option<UnboundedSender<()>>,
+
+	// --lms-batch: how long to collect commands before sending them in one request, and those collected so far
+	batch: Option<Duration>,
+	queue: Rc<RefCell<Vec<Value>>>,
+
+	// --lms-retry-base-ms: the delay before the first retry of a failed notification, --lms-retry-multiplier: by how much it grows every time
+	retry_base: Option<u64>,
+	retry_multiplier: u64
 }
 
+// how often to retry a notification LMS didn't accept, and by how much the delay grows every time unless told otherwise
+const RETRIES: u32 = 3;
+pub const RETRY_MULTIPLIER: u64 = 3;
+
 #[allow(unused)]
 impl LMS {
-	pub fn new(base_url: Option<String>, player_mac: Option<String>, auth: Option<String>) -> LMS {
+	pub fn new(base_url: Option<String>, player_mac: Option<String>, auth: Option<String>, socket: Option<String>, uri_form: bool, pass_through: bool, batch: Option<u64>) -> LMS {
+		#[cfg(not(unix))]
+		{
+			if socket.is_some() {
+				writeln!(stderr(), "warning: --lms-socket is only supported on Unix systems - using TCP").unwrap();
+			}
+		}
+
+		// the value ends up in an HTTP header as is - a line break would let it add headers of its own
+		if let Some(ref auth) = auth {
+			if auth.is_empty() || !auth.chars().all(|c| c.is_ascii_graphic()) {
+				writeln!(stderr(), "error: invalid --lms-auth value - expected the base64 encoded \"username:password\"").unwrap();
+				exit(1);
+			}
+		}
+
+		let base_url = format!("http://{}/jsonrpc.js", base_url.unwrap_or("localhost:9000".to_string()));
+
+		if Uri::from_str(&base_url).is_err() {
+			writeln!(stderr(), "error: invalid LMS address '{}' - expected eg. localhost:9000", base_url).unwrap();
+			exit(1);
+		}
+
 		LMS {
-			base_url: Some(format!("http://{}/jsonrpc.js", base_url.unwrap_or("localhost:9000".to_string()))),
-			player_mac: player_mac,
-			auth: auth
+			base_url: Some(base_url),
+			// a comma separated list of MAC addresses allows to control several players at once
+			player_macs: player_mac.unwrap_or("".to_string())
+				.split(',')
+				.map(|mac| mac.trim())
+				.filter(|mac| !mac.is_empty())
+				.map(|mac| {
+					normalize_mac(mac).unwrap_or_else(|| {
+						writeln!(stderr(), "warning: invalid player MAC address '{}' - expected eg. 00:04:20:12:34:56", mac).unwrap();
+						mac.to_string()
+					})
+				})
+				.collect(),
+			auth: auth,
+			socket: socket,
+			uri_form: uri_form,
+			pass_through: pass_through,
+			position: Position::new(),
+			in_flight: Rc::new(Cell::new(0)),
+			warned: Rc::new(Cell::new(false)),
+			unreachable: Rc::new(Cell::new(false)),
+			failures: None,
+			batch: batch.map(Duration::from_millis),
+			queue: Rc::new(RefCell::new(Vec::new())),
+			retry_base: None,
+			retry_multiplier: RETRY_MULTIPLIER
 		}
 	}
 
 	pub fn is_configured(&self) -> bool {
 		if self.base_url != None {
-			if self.player_mac != None {
+			if !self.player_macs.is_empty() {
 				return true;
 			}
 		}
@@ -38,78 +128,464 @@ impl LMS {
 		return false;
 	}
 
-	pub fn signal_event(&self, event: PlayerEvent, handle: Handle) {
-		let mut command = r#"["spottyconnect","change"]"#.to_string();
+	// for --dump-config - the credentials stay private
+	pub fn config_json(&self) -> Value {
+		json!({
+			"url": self.base_url,
+			"players": self.player_macs,
+			"auth": self.auth.as_ref().map(|_| "<redacted>"),
+			"socket": self.socket,
+			"batch": self.batch.map(|batch| batch.as_secs() * 1000 + (batch.subsec_nanos() / 1_000_000) as u64),
+			"retry_base_ms": self.retry_base,
+			"retry_multiplier": self.retry_multiplier
+		})
+	}
+
+	// get a message for every notification LMS didn't accept
+	pub fn failures(&mut self) -> UnboundedReceiver<()> {
+		let (sender, receiver) = mpsc::unbounded();
+		self.failures = Some(sender);
+		receiver
+	}
+
+	// retry failed notifications after base, base * multiplier, base * multiplier^2 milliseconds - enough for LMS to be back after a quick restart
+	pub fn retry_with_backoff(&mut self, base: u64, multiplier: u64) {
+		self.retry_base = Some(base);
+		self.retry_multiplier = multiplier;
+	}
+
+	pub fn pending(&self) -> usize {
+		self.in_flight.get() + self.queue.borrow().len()
+	}
+
+	pub fn signal_event(&mut self, event: PlayerEvent, handle: Handle) {
+		// built as JSON rather than pasted together, so whatever ends up in a command is escaped properly
+		let mut command = json!(["spottyconnect", "change"]);
 
 		match event {
 			PlayerEvent::Changed {
 				old_track_id,
 				new_track_id,
 			} => {
-				#[cfg(debug_assertions)]
 				info!("change: spotify:track:{} -> spotify:track:{}", old_track_id.to_base62(), new_track_id.to_base62());
-				command = format!(r#"["spottyconnect","change","{}","{}"]"#, new_track_id.to_base62().to_string(), old_track_id.to_base62().to_string());
+
+				// some restarts report a change to the very same track - nothing LMS would need to redraw for
+				if old_track_id == new_track_id {
+					return;
+				}
+
+				command = json!(["spottyconnect", "change", self.track_id(new_track_id), self.track_id(old_track_id)]);
+
+				// the new track is being fetched - let LMS show it right away, "start" follows once audio is flowing
+				self.notify(&json!(["spottyconnect", "loading", self.track_id(new_track_id)]), &handle);
 			}
 			PlayerEvent::Started { track_id } => {
-				#[cfg(debug_assertions)]
-				info!("play spotify:track:{}", track_id.to_base62());
-				command = format!(r#"["spottyconnect","start","{}"]"#, track_id.to_base62().to_string());
+				let position = self.position.current();
+				info!("play spotify:track:{} at {}ms", track_id.to_base62(), position);
+				// tell LMS where we (re-)started, in seconds, and what the audio is going to look like
+				command = json!(["spottyconnect", "start", self.track_id(track_id), position as f64 / 1000.0, self.audio_format()]);
 			}
 			PlayerEvent::Stopped { track_id } => {
-				#[cfg(debug_assertions)]
 				info!("stop spotify:track:{}", track_id.to_base62());
-				command = r#"["spottyconnect","stop"]"#.to_string();
+				command = json!(["spottyconnect", "stop"]);
 			}
 			PlayerEvent::Volume { volume } => {
-				#[cfg(debug_assertions)]
 				info!("volume {}", volume);
 				// we're not using the volume here, as LMS will read player state anyway
-				command = format!(r#"["spottyconnect","volume",{}]"#, volume.to_string());
+				command = json!(["spottyconnect", "volume", volume]);
 			}
 			PlayerEvent::Seek { position } => {
-				#[cfg(debug_assertions)]
 				info!("seek {}", position);
 				// we're not implementing the seek event here, as it's going to read player state anyway
-				command = r#"["spottyconnect","change"]"#.to_string();
+				command = json!(["spottyconnect", "change"]);
 			}
 		}
 
+		self.position.update(&event);
+		self.notify(&command, &handle);
+	}
+
+	// send a command to all our players - fire and forget
+	pub fn notify(&self, command: &Value, handle: &Handle) {
 		if !self.is_configured() {
-			#[cfg(debug_assertions)]
 			info!("LMS connection is not configured");
 			return;
 		}
 
-		#[cfg(debug_assertions)]
+		info!("Command to send to player: {}", command);
+
+		if let Some(batch) = self.batch {
+			let first = self.queue.borrow().is_empty();
+			self.queue.borrow_mut().push(command.clone());
+
+			// the first command of a batch starts the clock - whatever comes in until it strikes goes along
+			if first {
+				let lms = self.clone();
+				let flush_handle = handle.clone();
+
+				handle.spawn(Timeout::new(batch, handle).unwrap().then(move |_| {
+					lms.flush(&flush_handle);
+					Ok(())
+				}));
+			}
+
+			return;
+		}
+
+		for player_mac in &self.player_macs {
+			let body = self.body(player_mac, command, 1);
+			self.post(body.to_string(), handle);
+		}
+	}
+
+	// send the collected commands to every player as one JSON-RPC batch
+	fn flush(&self, handle: &Handle) {
+		let commands: Vec<Value> = self.queue.borrow_mut().drain(..).collect();
+
+		if commands.is_empty() {
+			return;
+		}
+
+		info!("Sending {} commands in one batch", commands.len());
+
+		for player_mac in &self.player_macs {
+			let requests: Vec<Value> = commands.iter().enumerate()
+				.map(|(index, command)| self.body(player_mac, command, index + 1))
+				.collect();
+
+			self.post(Value::Array(requests).to_string(), handle);
+		}
+	}
+
+	fn post(&self, body: String, handle: &Handle) {
 		info!("Base URL to talk to LMS: {}", self.base_url.clone().unwrap());
 
 		if let Some(ref base_url) = self.base_url {
-			#[cfg(debug_assertions)]
-			info!("Player MAC address to control: {}", self.player_mac.clone().unwrap());
-			if let Some(ref player_mac) = self.player_mac {
+			// ugh... just send that thing and don't care about the rest...
+			let in_flight = self.in_flight.clone();
+			in_flight.set(in_flight.get() + 1);
 
-				let client = Client::new(&handle);
+			let warned = self.warned.clone();
+			let unreachable = self.unreachable.clone();
+			let failures = self.failures.clone();
+			let url = base_url.clone();
 
-				#[cfg(debug_assertions)]
-				info!("Command to send to player: {}", command);
+			let lms = self.clone();
+			let retry_handle = handle.clone();
+			let retry_url = base_url.clone();
 
-				let json = format!(r#"{{"id": 1,"method":"slim.request","params":["{}",{}]}}"#, player_mac, command);
-				let uri = Uri::from_str(base_url).unwrap();
-				let mut req = Request::new(Method::Post, uri);
+			let attempts = future::loop_fn(0, move |attempt| {
+				let retry_handle = retry_handle.clone();
+				let retry_base = lms.retry_base;
+				let retry_multiplier = lms.retry_multiplier;
 
-				if let Some(ref auth) = self.auth {
-					req.headers_mut().set(Authorization(format!("Basic {}", auth).to_owned()));
-				}
+				lms.send(lms.request(&retry_url, body.clone()), &retry_handle)
+					.and_then(|res| {
+						let success = res.status().is_success();
+						res.body().concat2().map(move |body| (success, body))
+					})
+					.then(move |result| {
+						let failed = match result {
+							Ok((success, _)) => !success,
+							Err(_) => true
+						};
 
-				req.headers_mut().set_raw("X-Scanner", "1");
-				req.headers_mut().set(ContentType::json());
-				req.headers_mut().set(ContentLength(json.len() as u64));
-				req.set_body(json);
+						match retry_base {
+							Some(base) if failed && attempt < RETRIES => {
+								let delay = retry_delay(base, retry_multiplier, attempt);
 
-				// ugh... just send that thing and don't care about the rest...
-				let post = client.request(req).map(|_| ()).map_err(|_| ());
-				handle.spawn(post);
+								#[cfg(debug_assertions)]
+								info!("LMS didn't take the notification - retrying in {}ms ({}/{})", delay, attempt + 1, RETRIES);
+
+								let retry = Timeout::new(Duration::from_millis(delay), &retry_handle).unwrap()
+									.then(move |_| Ok(future::Loop::Continue(attempt + 1)));
+
+								future::Either::A(retry)
+							}
+							_ => future::Either::B(future::ok(future::Loop::Break(result)))
+						}
+					})
+			});
+
+			let post = attempts
+				.and_then(move |result| {
+					in_flight.set(in_flight.get() - 1);
+
+					let success = match result {
+						Ok((success, body)) => {
+							unreachable.set(false);
+
+							// a web UI (or anything else) on the wrong port would happily answer with a 200
+							if !is_jsonrpc(&body) && !warned.get() {
+								warned.set(true);
+								writeln!(stderr(), "warning: {} didn't answer with JSON-RPC - is --lms pointing to the right host and port?", url).unwrap();
+							}
+
+							success
+						}
+						Err(e) => {
+							if !unreachable.get() {
+								unreachable.set(true);
+								writeln!(stderr(), "error: failed to notify LMS: {}", connection_error(&url, &e)).unwrap();
+							}
+
+							false
+						}
+					};
+
+					if !success {
+						if let Some(failures) = failures {
+							let _ = failures.unbounded_send(());
+						}
+					}
+
+					Ok(())
+				});
+			handle.spawn(post);
+		}
+	}
+
+	// older plugins expect base62 IDs, newer ones can deal with full URIs
+	fn track_id(&self, track_id: SpotifyId) -> String {
+		if self.uri_form {
+			track_id.to_uri()
+		}
+		else {
+			track_id.to_base62()
+		}
+	}
+
+	// librespot doesn't tell, but Spotify's streams all are 44.1kHz stereo - either passed through as OGG Vorbis, or decoded to 16 bit PCM
+	fn audio_format(&self) -> Value {
+		json!({
+			"format": if self.pass_through { "ogg" } else { "pcm" },
+			"rate": 44100,
+			"channels": 2
+		})
+	}
+
+	// send a harmless version query to LMS and report back what we got - used by --test-lms
+	pub fn test_connection(&self, handle: Handle) -> Box<Future<Item=Value, Error=()>> {
+		let base_url = self.base_url.clone().unwrap();
+		let player_mac = self.player_macs.first().cloned().unwrap_or("".to_string());
+
+		let req = self.request(&base_url, self.body(&player_mac, &json!(["version", "?"]), 1).to_string());
+
+		let url = base_url.clone();
+		let player = player_mac.clone();
+
+		let result = self.send(req, &handle)
+			.and_then(|res| {
+				let status = res.status().as_u16();
+				res.body().concat2().map(move |body| (status, body))
+			})
+			.then(move |result| {
+				let data = match result {
+					Ok((status, body)) => {
+						let version = serde_json::from_slice::<Value>(&body).ok()
+							.and_then(|response| response.pointer("/result/_version").cloned())
+							.unwrap_or(Value::Null);
+
+						json!({
+							"url": url,
+							"player": player,
+							"status": status,
+							"jsonrpc": is_jsonrpc(&body),
+							"version": version
+						})
+					}
+					Err(e) => {
+						json!({
+							"url": url,
+							"player": player,
+							"error": connection_error(&url, &e)
+						})
+					}
+				};
+
+				Ok(data)
+			});
+
+		Box::new(result)
+	}
+
+	fn send(&self, req: Request, handle: &Handle) -> FutureResponse {
+		#[cfg(unix)]
+		{
+			if let Some(ref socket) = self.socket {
+				let connector = UnixConnector {
+					path: PathBuf::from(socket),
+					handle: handle.clone()
+				};
+
+				return Client::configure().connector(connector).build(handle).request(req);
+			}
+		}
+
+		Client::new(handle).request(req)
+	}
+
+	fn body(&self, player_mac: &str, command: &Value, id: usize) -> Value {
+		info!("Player MAC address to control: {}", player_mac);
+
+		json!({
+			"id": id,
+			"method": "slim.request",
+			"params": [player_mac, command]
+		})
+	}
+
+	fn request(&self, base_url: &str, json: String) -> Request {
+		let uri = Uri::from_str(base_url).unwrap();
+		let mut req = Request::new(Method::Post, uri);
+
+		if let Some(ref auth) = self.auth {
+			req.headers_mut().set(Authorization(format!("Basic {}", auth).to_owned()));
+		}
+
+		req.headers_mut().set_raw("X-Scanner", "1");
+		req.headers_mut().set(ContentType::json());
+		req.headers_mut().set(ContentLength(json.len() as u64));
+		req.set_body(json);
+
+		req
+	}
+}
+
+// hyper doesn't tell a host name which doesn't resolve from a host which doesn't answer - a typo in --lms is worth pointing out
+fn connection_error(url: &str, e: &hyper::Error) -> String {
+	if let hyper::Error::Io(ref io) = *e {
+		if is_lookup_failure(io) {
+			// an IP address isn't looked up - whatever went wrong, it wasn't that
+			let host = Uri::from_str(url).ok().and_then(|uri| uri.host().map(|host| host.to_string()))
+				.filter(|host| host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>().is_err());
+
+			if let Some(host) = host {
+				return format!("could not resolve host '{}' - please check the --lms option", host);
 			}
 		}
 	}
+
+	e.to_string()
+}
+
+// the connector resolves the host name before it connects, and passes on what getaddrinfo() said - Windows reports its own error codes
+fn is_lookup_failure(e: &io::Error) -> bool {
+	const WSAHOST_NOT_FOUND: i32 = 11001;
+	const WSANO_DATA: i32 = 11004;
+
+	e.to_string().starts_with("failed to lookup address information")
+		|| (cfg!(windows) && e.raw_os_error().map_or(false, |code| code == WSAHOST_NOT_FOUND || code == WSANO_DATA))
+}
+
+// LMS answers with the request's id and a result - anything else isn't what we're talking to. Batches are answered by a list of those.
+fn is_jsonrpc(body: &[u8]) -> bool {
+	let is_response = |response: &Value| response.get("result").is_some() || response.get("id").is_some();
+
+	match serde_json::from_slice::<Value>(body) {
+		Ok(Value::Array(responses)) => responses.first().map_or(false, |response| is_response(response)),
+		Ok(response) => is_response(&response),
+		Err(_) => false
+	}
+}
+
+// how long to wait before the given retry - a silly multiplier must not overflow
+fn retry_delay(base: u64, multiplier: u64, attempt: u32) -> u64 {
+	multiplier.checked_pow(attempt).map_or(u64::max_value(), |factor| base.saturating_mul(factor))
+}
+
+// LMS identifies players by their lowercase, colon separated MAC address - accept dashes and upper case, too
+fn normalize_mac(mac: &str) -> Option<String> {
+	let parts: Vec<&str> = mac.trim().split(|c| c == ':' || c == '-').collect();
+
+	if parts.len() != 6 || parts.iter().any(|part| part.len() != 2 || !part.chars().all(|c| c.is_digit(16))) {
+		return None;
+	}
+
+	Some(parts.join(":").to_lowercase())
+}
+
+// connect to a local LMS (or relay) through a Unix domain socket - the URI is only used for the request line
+#[cfg(unix)]
+struct UnixConnector {
+	path: PathBuf,
+	handle: Handle
+}
+
+#[cfg(unix)]
+impl Service for UnixConnector {
+	type Request = Uri;
+	type Response = UnixStream;
+	type Error = io::Error;
+	type Future = future::FutureResult<UnixStream, io::Error>;
+
+	fn call(&self, _uri: Uri) -> Self::Future {
+		future::result(UnixStream::connect(&self.path, &self.handle))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::ToSocketAddrs;
+	use tokio_core::reactor::Core;
+	use librespot::core::spotify_id::SpotifyAudioType;
+
+	fn track(id: u128) -> SpotifyId {
+		SpotifyId { id: id, audio_type: SpotifyAudioType::Track }
+	}
+
+	// with a batch window nothing is sent right away - the queue shows what would have been
+	#[test]
+	fn same_track_change_not_sent() {
+		let core = Core::new().unwrap();
+		let mut lms = LMS::new(None, Some("00:04:20:12:34:56".to_string()), None, None, false, false, Some(1000));
+
+		lms.signal_event(PlayerEvent::Changed { old_track_id: track(1), new_track_id: track(1) }, core.handle());
+		assert_eq!(lms.pending(), 0);
+
+		lms.signal_event(PlayerEvent::Changed { old_track_id: track(1), new_track_id: track(2) }, core.handle());
+		let commands: Vec<Value> = lms.queue.borrow().clone();
+		assert_eq!(commands.len(), 2);
+		assert_eq!(commands[0][1], "loading");
+		assert_eq!(commands[1], json!(["spottyconnect", "change", track(2).to_base62(), track(1).to_base62()]));
+	}
+
+	#[test]
+	fn body_escapes_quotes_and_backslashes() {
+		let lms = LMS::new(None, Some("00:04:20:12:34:56".to_string()), None, None, false, false, None);
+		let command = json!(["spottyconnect", "change", "the \"quoted\" \\ track"]);
+
+		let body: Value = serde_json::from_str(&lms.body("00:04:20:12:34:56", &command, 1).to_string()).unwrap();
+
+		assert_eq!(body["params"][1], command);
+		assert_eq!(body["params"][1][2], "the \"quoted\" \\ track");
+	}
+
+	#[test]
+	fn connection_errors_classified() {
+		let url = "http://lms.invalid:9000/jsonrpc.js";
+
+		let refused = hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused"));
+		assert_eq!(connection_error(url, &refused), refused.to_string());
+
+		let lookup = hyper::Error::Io(io::Error::new(io::ErrorKind::Other, "failed to lookup address information: Name or service not known"));
+		assert_eq!(connection_error(url, &lookup), "could not resolve host 'lms.invalid' - please check the --lms option");
+		assert_eq!(connection_error("http://10.0.0.99:9000/jsonrpc.js", &lookup), lookup.to_string());
+
+		let unreachable = hyper::Error::Io(io::Error::new(io::ErrorKind::Other, "No route to host"));
+		assert_eq!(connection_error("http://10.0.0.99:9000/jsonrpc.js", &unreachable), unreachable.to_string());
+		assert_eq!(connection_error(url, &unreachable), unreachable.to_string());
+
+		assert!(is_lookup_failure(&("lms.invalid", 9000).to_socket_addrs().unwrap_err()));
+	}
+
+	#[test]
+	fn retry_delay_grows_by_multiplier() {
+		assert_eq!(retry_delay(100, RETRY_MULTIPLIER, 0), 100);
+		assert_eq!(retry_delay(100, RETRY_MULTIPLIER, 2), 900);
+		assert_eq!(retry_delay(100, 2, 2), 400);
+		assert_eq!(retry_delay(100, 1, 2), 100);
+		assert_eq!(retry_delay(100, u64::max_value(), 2), u64::max_value());
+	}
 }
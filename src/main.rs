@@ -1,37 +1,50 @@
-#[cfg(debug_assertions)]
 #[macro_use] extern crate log;
+extern crate base64;
 extern crate crypto;
-#[cfg(debug_assertions)]
 extern crate env_logger;
 extern crate futures;
 extern crate getopts;
 extern crate hyper;
 extern crate librespot;
+extern crate protobuf;
 extern crate rpassword;
 #[macro_use]
 extern crate serde_json;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_signal;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(unix)]
+extern crate tokio_uds;
 
-use futures::{Future, Async, Poll, Stream};
+use futures::{future, Future, Async, Poll, Stream};
 use futures::sync::mpsc::UnboundedReceiver;
-#[cfg(debug_assertions)]
+use std::cmp;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, stderr, Write};
-use std::path::PathBuf;
-use std::process::exit;
-use std::str::FromStr;
-use tokio_core::reactor::{Handle, Core};
+use std::path::{Path, PathBuf};
+use std::process::{self, exit};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_core::reactor::{Handle, Core, Timeout};
 use tokio_io::IoStream;
 use std::mem;
+use std::net::{IpAddr, SocketAddr};
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use protobuf::ProtobufEnum;
+use serde_json::Value;
 
 use librespot::core::authentication::{get_credentials, Credentials};
 use librespot::core::cache::Cache;
 use librespot::core::config::{DeviceType, SessionConfig, ConnectConfig};
+use librespot::core::mercury::MercuryError;
 use librespot::core::session::Session;
 
 use librespot::playback::audio_backend::{self};
@@ -41,11 +54,27 @@ use librespot::playback::mixer::{self, MixerConfig};
 use librespot::playback::player::{Player, PlayerEvent};
 use librespot::connect::spirc::{Spirc, SpircTask};
 
-use librespot::core::spotify_id::SpotifyId;
+use librespot::core::spotify_id::{FileId, SpotifyAudioType, SpotifyId};
+use librespot::protocol::authentication::AuthenticationType;
 
 mod lms;
 use lms::LMS;
 
+mod health;
+use health::Health;
+
+mod metadata;
+use metadata::MetadataCache;
+
+mod position;
+use position::Position;
+
+mod remote;
+use remote::Context;
+
+mod script;
+use script::EventScript;
+
 const VERSION: &'static str = concat!(env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
 
 #[cfg(debug_assertions)]
@@ -53,6 +82,104 @@ const DEBUGMODE: bool = true;
 #[cfg(not(debug_assertions))]
 const DEBUGMODE: bool = false;
 
+// reconnect delays double with every attempt, up to 2^6 = 64 seconds
+const RECONNECT_MAX_BACKOFF: u32 = 6;
+
+// number of failed connection attempts before --force-ap-retry switches to another access point
+const AP_RETRY_AFTER: u32 = 2;
+
+// the features the LMS plugin detects through --check, and the command line option implementing each of them
+const CAPABILITIES: &'static [(&'static str, &'static str)] = &[
+	("lms-auth", "lms-auth"),
+	("volume-normalisation", "enable-volume-normalisation"),
+	("ogg-direct", "pass-through"),
+	("save-token", "save-token"),
+	("test-lms", "test-lms"),
+	("event-script", "event-script"),
+	("list-devices", "list-devices"),
+	("transfer-to", "transfer-to"),
+	("credentials-json", "credentials-json"),
+	("get-state", "get-state"),
+	("prefetch", "prefetch"),
+	("metadata-header", "metadata-header"),
+	("session-timeout", "session-timeout"),
+	("log-level", "log-level"),
+	("dump-config", "dump-config"),
+	("metered", "metered"),
+	("once", "once"),
+	("event-uri-form", "event-uri-form"),
+	("health-port", "health-port"),
+	("health-address", "health-address"),
+	("reconnect-jitter", "reconnect-jitter"),
+	("open-files-limit", "open-files-limit"),
+	("startup-json", "startup-json"),
+	("on-lms-failure", "on-lms-failure"),
+	("metadata-cache", "metadata-cache"),
+	("play-context", "play-context"),
+	("start-index", "start-index"),
+	("exit-on-idle", "exit-on-idle"),
+	("cache-wait", "cache-wait"),
+	("filter-explicit", "filter-explicit"),
+	("normalisation-pregain", "normalisation-pregain"),
+	("resume-on-reconnect", "resume-on-reconnect"),
+	("lms-batch", "lms-batch"),
+	("lms-retry", "lms-retry-base-ms"),
+	("lms-retry-multiplier", "lms-retry-multiplier"),
+	("discovery-name-unique", "force-discovery-name-unique"),
+	("passthrough-chunk-size", "passthrough-chunk-size"),
+	("refresh-credentials", "refresh-credentials"),
+	("print-events", "print-events"),
+	("device-type", "device-type"),
+	("resume-last", "resume-last"),
+	("probe-metadata-batch", "probe-metadata-batch"),
+	("connect-retry-on-auth-error", "connect-retry-on-auth-error"),
+	("syslog", "syslog")
+];
+
+// the features which came without an option of their own, and the check against the code implementing each of them
+const FEATURES: &'static [(&'static str, fn() -> bool)] = &[
+	("podcasts", plays_podcasts),
+	("bitrate-auto", adapts_bitrate)
+];
+
+// options which only make sense together with another one - rather tell than silently ignore them
+const OPTION_DEPENDENCIES: &'static [(&'static str, &'static str)] = &[
+	("start-position", "single-track"),
+	("initial-seek-retry", "single-track"),
+	("cache-clear-credentials", "cache-clear"),
+	("metadata-cache-ttl", "metadata-cache"),
+	("start-index", "play-context"),
+	("health-address", "health-port")
+];
+
+// with --bitrate auto a connection which lasted this long (in seconds) before it dropped is considered good enough for a higher bitrate
+const ADAPTIVE_BITRATE_STABLE: u64 = 3600;
+
+// how often, and how long (in seconds) to wait for a mercury request before giving up - and how long (in ms) to pause before trying again
+const MERCURY_ATTEMPTS: u32 = 3;
+const MERCURY_TIMEOUT: u64 = 10;
+const MERCURY_RETRY_DELAY: u64 = 500;
+
+// how long (in seconds) cached track metadata is considered up to date, unless told otherwise by --metadata-cache-ttl
+const METADATA_CACHE_TTL: u64 = 86400;
+
+// how often, and how long (in ms) apart, to look for a prefetched file in the cache folder - the cache writes it after the player is done
+const CACHE_WRITE_ATTEMPTS: u32 = 50;
+const CACHE_WRITE_DELAY: u64 = 100;
+
+// milliseconds to go back when resuming a track after a reconnect - the last bit before the drop might not have been heard
+const RESUME_REWIND: u32 = 3000;
+
+// number of tracks handed over to the player at once with --play-context - large playlists are played in windows of this size
+const CONTEXT_WINDOW: usize = 100;
+
+// --resume-last: where in the cache folder we keep what we were playing
+const LAST_PLAYED_FILE: &'static str = "last_played.json";
+
+// seconds to wait before trying to restart a failed discovery stream, and how often to try before giving up
+const DISCOVERY_RETRY_DELAY: u64 = 5;
+const DISCOVERY_MAX_ATTEMPTS: u32 = 5;
+
 #[cfg(target_os="windows")]
 const NULLDEVICE: &'static str = "NUL";
 #[cfg(not(target_os="windows"))]
@@ -64,6 +191,55 @@ fn device_id(name: &str) -> String {
 	h.result_str()
 }
 
+// several instances sharing a --name still run on different hosts, or at least use different cache folders - neither changes across restarts
+fn instance_suffix(cache_location: Option<&str>) -> String {
+	let cache = cache_location.map(|cache| fs::canonicalize(cache).unwrap_or(PathBuf::from(cache)));
+
+	let mut h = Sha1::new();
+	h.input_str(&host_name());
+	h.input_str(&cache.map(|cache| cache.display().to_string()).unwrap_or("".to_string()));
+	h.result_str()[..4].to_string()
+}
+
+#[cfg(unix)]
+fn host_name() -> String {
+	let mut name = [0u8; 256];
+
+	if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+		return "".to_string();
+	}
+
+	let length = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+	String::from_utf8_lossy(&name[..length]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn host_name() -> String {
+	env::var("COMPUTERNAME").unwrap_or("".to_string())
+}
+
+// only advertise a capability if its option is known to the parser - they can't get out of sync this way
+fn capabilities(opts: &getopts::Options) -> Value {
+	let mut capabilities = serde_json::Map::new();
+	capabilities.insert("version".to_string(), json!(env!("CARGO_PKG_VERSION").to_string()));
+	capabilities.insert("debug".to_string(), json!(DEBUGMODE));
+
+	for &(capability, option) in CAPABILITIES {
+		let implemented = match opts.parse(vec![format!("--{}", option)]) {
+			Err(getopts::Fail::UnrecognizedOption(_)) => false,
+			_ => true
+		};
+
+		capabilities.insert(capability.to_string(), json!(implemented));
+	}
+
+	for &(capability, implemented) in FEATURES {
+		capabilities.insert(capability.to_string(), json!(implemented()));
+	}
+
+	Value::Object(capabilities)
+}
+
 fn usage(program: &str, opts: &getopts::Options) -> String {
 	println!("{}", VERSION.to_string());
 
@@ -71,27 +247,556 @@ fn usage(program: &str, opts: &getopts::Options) -> String {
 	opts.usage(&brief)
 }
 
-#[cfg(debug_assertions)]
-fn setup_logging(verbose: bool) {
+// remove what librespot stores in the cache folder: audio files and optionally the credentials
+fn clear_cache(cache_dir: &Path, clear_credentials: bool) -> Vec<String> {
+	let mut removed = Vec::new();
+
+	let files = cache_dir.join("files");
+	if files.is_dir() {
+		match fs::remove_dir_all(&files) {
+			Ok(_) => removed.push(files.display().to_string()),
+			Err(e) => writeln!(stderr(), "error: failed to remove {}: {}", files.display(), e).unwrap()
+		}
+	}
+
+	let credentials = cache_dir.join("credentials.json");
+	if clear_credentials && credentials.is_file() {
+		match fs::remove_file(&credentials) {
+			Ok(_) => removed.push(credentials.display().to_string()),
+			Err(e) => writeln!(stderr(), "error: failed to remove {}: {}", credentials.display(), e).unwrap()
+		}
+	}
+
+	removed
+}
+
+// librespot keeps every complete audio file in the cache folder's "files" directory, named after its file ID
+fn cached_file(cache_dir: &Path, file: &FileId) -> PathBuf {
+	let name = file.to_base16();
+	cache_dir.join("files").join(&name[0..2]).join(&name[2..])
+}
+
+// a cache folder on a network share might not be mounted yet when we're started - rather wait than lose the credentials.
+// Creating the folder ourselves would put it on the mount point, hidden once the share is mounted.
+fn wait_for_cache(cache_dir: &Path, timeout: Duration) {
+	if timeout == Duration::from_secs(0) || cache_dir.is_dir() {
+		return;
+	}
+
+	writeln!(stderr(), "Waiting up to {}s for the cache folder {} to become available...", timeout.as_secs(), cache_dir.display()).unwrap();
+
+	let deadline = Instant::now() + timeout;
+	while !cache_dir.is_dir() {
+		if Instant::now() >= deadline {
+			writeln!(stderr(), "error: the cache folder {} isn't available after {}s - is it on a share which isn't mounted?", cache_dir.display(), timeout.as_secs()).unwrap();
+			exit(1);
+		}
+
+		thread::sleep(Duration::from_secs(1));
+	}
+
+	writeln!(stderr(), "Cache folder {} is available", cache_dir.display()).unwrap();
+}
+
+// only accept the bitrates librespot can actually stream - lossless isn't available (yet)
+fn parse_bitrate(bitrate: &str) -> Bitrate {
+	match bitrate.trim().to_lowercase().as_str() {
+		"96" => Bitrate::Bitrate96,
+		"160" => Bitrate::Bitrate160,
+		"320" => Bitrate::Bitrate320,
+		"lossless" | "flac" | "hifi" | "1411" | "24bit" => {
+			writeln!(stderr(), "error: lossless streaming is not supported - use 96, 160 or 320").unwrap();
+			exit(1);
+		}
+		_ => {
+			writeln!(stderr(), "error: invalid bitrate '{}' - use 96, 160 or 320", bitrate).unwrap();
+			exit(1);
+		}
+	}
+}
+
+// --client-id wins over the ID embedded at build time (build.rs creates an empty one) - blank values don't count
+fn resolve_client_id(client_id: Option<String>, embedded: &str) -> Option<String> {
+	client_id.into_iter()
+		.chain(Some(embedded.to_string()))
+		.map(|id| id.trim().to_string())
+		.find(|id| !id.is_empty())
+}
+
+// read credentials in the format librespot uses for credentials.json: {"username":"...","auth_type":1,"auth_data":"<base64>"}
+fn parse_credentials_json(data: &str) -> Option<Credentials> {
+	let value = serde_json::from_str::<Value>(data).ok()?;
+
+	let username = value.get("username")?.as_str()?.to_string();
+	let auth_type = AuthenticationType::from_i32(value.get("auth_type")?.as_i64()? as i32)?;
+	let auth_data = base64::decode(value.get("auth_data")?.as_str()?).ok()?;
+
+	Some(Credentials {
+		username: username,
+		auth_type: auth_type,
+		auth_data: auth_data
+	})
+}
+
+// percent-encode everything but unreserved characters - and the comma separating the scopes
+fn url_encode(value: &str) -> String {
+	value.bytes().map(|b| match b {
+		b'A' ..= b'Z' | b'a' ..= b'z' | b'0' ..= b'9' | b'-' | b'_' | b'.' | b'~' | b',' => (b as char).to_string(),
+		_ => format!("%{:02X}", b)
+	}).collect()
+}
+
+fn exit_with_error(error: &str, detail: String) -> ! {
+	println!("{}", json!({ "error": error, "detail": detail }).to_string());
+	exit(1);
+}
+
+// mercury requests can hang or fail while Spotify is having a bad day - give them a few chances, and only so much time each.
+// What is what we're asking for, for the log.
+fn mercury_with_retry<T, R, F>(what: String, handle: &Handle, mut request: F) -> Box<Future<Item=T, Error=MercuryError>>
+	where T: 'static, R: Future<Item=T, Error=MercuryError> + 'static, F: FnMut() -> R + 'static
+{
+	let timeout = Duration::from_secs(MERCURY_TIMEOUT);
+	let timeout_handle = handle.clone();
+
+	with_retries(MERCURY_ATTEMPTS, Duration::from_millis(MERCURY_RETRY_DELAY), handle, move |attempt| {
+		if attempt > 1 {
+			info!("Mercury request failed, retrying ({}/{}): {}", attempt, MERCURY_ATTEMPTS, what);
+		}
+
+		Box::new(request()
+			.select2(Timeout::new(timeout, &timeout_handle).unwrap())
+			.then(|result| match result {
+				Ok(future::Either::A((response, _))) => Ok(response),
+				_ => Err(MercuryError)
+			}))
+	})
+}
+
+// run a request up to the given number of attempts (but at least once), pausing a little before every retry
+fn with_retries<T, E, F>(attempts: u32, delay: Duration, handle: &Handle, mut request: F) -> Box<Future<Item=T, Error=E>>
+	where T: 'static, E: 'static, F: FnMut(u32) -> Box<Future<Item=T, Error=E>> + 'static
+{
+	let handle = handle.clone();
+
+	Box::new(future::loop_fn(1, move |attempt| {
+		let handle = handle.clone();
+
+		request(attempt).then(move |result| match result {
+			Err(_) if attempt < attempts => {
+				let retry = Timeout::new(delay, &handle).unwrap()
+					.then(move |_| Ok(future::Loop::Continue(attempt + 1)));
+
+				future::Either::A(retry)
+			}
+			result => future::Either::B(future::result(result.map(future::Loop::Break)))
+		})
+	}))
+}
+
+// Spotify might grant fewer scopes than we asked for - add what we got, and what's missing, to the token data
+fn verify_scopes(token: &str, requested: &str) -> String {
+	let mut data = match serde_json::from_str::<Value>(token) {
+		Ok(Value::Object(data)) => data,
+		_ => return token.to_string()
+	};
+
+	let granted: Vec<String> = match data.get("scope") {
+		Some(&Value::Array(ref scopes)) => scopes.iter().filter_map(|scope| scope.as_str()).map(|scope| scope.to_string()).collect(),
+		Some(&Value::String(ref scopes)) => scopes.split(|c| c == ' ' || c == ',').filter(|scope| !scope.is_empty()).map(|scope| scope.to_string()).collect(),
+		// nothing to compare against
+		_ => return token.to_string()
+	};
+
+	let missing: Vec<&str> = requested.split(',')
+		.map(|scope| scope.trim())
+		.filter(|scope| !scope.is_empty() && !granted.iter().any(|granted| granted == scope))
+		.collect();
+
+	if !missing.is_empty() {
+		writeln!(stderr(), "warning: Spotify didn't grant the requested scopes {}", missing.join(", ")).unwrap();
+	}
+
+	data.insert("grantedScopes".to_string(), json!(granted));
+	data.insert("missingScopes".to_string(), json!(missing));
+
+	Value::Object(data).to_string()
+}
+
+// the access point handshake isn't TLS, but SSL inspecting proxies and firewalls break it all the same - say so
+fn connect_error(e: &io::Error) -> String {
+	if is_auth_error(e) {
+		return format!("{} - check the username and password, or authenticate again", e);
+	}
+
+	match e.kind() {
+		io::ErrorKind::UnexpectedEof => format!("{} - the access point closed the connection during the handshake", e),
+		io::ErrorKind::InvalidData => format!("{} - unexpected handshake response, a proxy or firewall might be intercepting the connection", e),
+		io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut => format!("{} - try another port using --ap-port", e),
+		_ => e.to_string()
+	}
+}
+
+// there's no point in trying the same credentials again once Spotify has turned them down - anything else might go away
+fn is_auth_error(e: &io::Error) -> bool {
+	let message = e.to_string();
+	e.kind() == io::ErrorKind::PermissionDenied || message.contains("Authentication failed") || message.contains("BadCredentials")
+}
+
+fn connect_failure(e: &io::Error) -> &'static str {
+	if is_auth_error(e) { "invalid_credentials" } else { "connect_failed" }
+}
+
+// stdout is carrying the audio data in single track mode - report errors on stderr
+fn single_track_error(error: &str, detail: String) -> ! {
+	writeln!(stderr(), "{}", json!({ "error": error, "detail": detail }).to_string()).unwrap();
+	exit(1);
+}
+
+// --disable-credential-cache: the credentials file as we found it at startup - maybe stored earlier with --authenticate
+#[derive(Clone)]
+struct CredentialsFile {
+	path: PathBuf,
+	original: Option<Vec<u8>>
+}
+
+impl CredentialsFile {
+	fn new(path: PathBuf) -> CredentialsFile {
+		let original = fs::read(&path).ok();
+		CredentialsFile { path: path, original: original }
+	}
+}
+
+// librespot stores the credentials whenever it connects, there's no way to keep it from doing so - put back what was there before
+fn forget_credentials(credentials_file: &Option<CredentialsFile>) {
+	if let Some(ref file) = *credentials_file {
+		let current = fs::read(&file.path).ok();
+
+		if current == file.original {
+			return;
+		}
+
+		let result = match file.original {
+			Some(ref original) => File::create(&file.path).and_then(|mut f| f.write_all(original)),
+			None => fs::remove_file(&file.path)
+		};
+
+		if let Err(e) = result {
+			writeln!(stderr(), "error: failed to restore {}: {}", file.path.display(), e).unwrap();
+		}
+	}
+}
+
+// the modes doing a single job need credentials - fail is how they report errors, on stdout or stderr
+fn credentials_or_exit(credentials: Option<Credentials>, fail: fn(&str, String) -> !) -> Credentials {
+	credentials.unwrap_or_else(|| fail("missing_credentials", "Use --cache or --username/--password to provide credentials".to_string()))
+}
+
+// connect for one of those jobs, or tell why we couldn't
+fn connect_or_exit(core: &mut Core, session_config: &SessionConfig, credentials: Option<Credentials>, cache: &Option<Cache>, discard_credentials: &Option<CredentialsFile>, fail: fn(&str, String) -> !) -> Session {
+	let credentials = credentials_or_exit(credentials, fail);
+	let handle = core.handle();
+
+	let session = core.run(Session::connect(session_config.clone(), credentials, cache.clone(), handle))
+		.unwrap_or_else(|e| fail(connect_failure(&e), connect_error(&e)));
+
+	forget_credentials(discard_credentials);
+	session
+}
+
+// LMS hands out spotty:// (or spotify://) URLs - librespot only understands spotify: URIs
+fn parse_track_id(track_id: &str) -> Option<SpotifyId> {
+	SpotifyId::from_uri(&track_id.replace("spotty://", "spotify:").replace("://", ":")).ok()
+}
+
+fn plays_podcasts() -> bool {
+	parse_track_id("spotify:episode:4rOoJ6Egrf8K2IrywzwOMk").map_or(false, |id| id.audio_type == SpotifyAudioType::Podcast)
+}
+
+fn is_adaptive_bitrate(bitrate: &str) -> bool {
+	bitrate.trim() == "auto"
+}
+
+fn adapts_bitrate() -> bool {
+	is_adaptive_bitrate("auto")
+}
+
+// librespot keeps a file open for every track it's fetching - the default limit on some NAS and BSD systems is too low for that
+#[cfg(unix)]
+fn set_open_files_limit(limit: u64) {
+	let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+
+	if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+		writeln!(stderr(), "error: can't read the open files limit: {}", io::Error::last_os_error()).unwrap();
+		return;
+	}
+
+	// only root could go beyond the hard limit
+	if limit as libc::rlim_t > rlim.rlim_max {
+		writeln!(stderr(), "warning: open files limit {} exceeds the system's maximum of {}", limit, rlim.rlim_max).unwrap();
+	}
+
+	rlim.rlim_cur = cmp::min(limit as libc::rlim_t, rlim.rlim_max);
+
+	if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) } != 0 {
+		writeln!(stderr(), "error: can't set the open files limit: {}", io::Error::last_os_error()).unwrap();
+	}
+}
+
+#[cfg(not(unix))]
+fn set_open_files_limit(_limit: u64) {
+	writeln!(stderr(), "warning: --open-files-limit is only supported on Unix systems").unwrap();
+}
+
+// --print-events: everything we know about an event, with track IDs in both forms LMS might be dealing with
+fn print_event(event: &PlayerEvent) {
+	let track = |track_id: &SpotifyId| format!("{} ({})", track_id.to_base62(), track_id.to_uri());
+
+	let details = match *event {
+		PlayerEvent::Changed { ref old_track_id, ref new_track_id } => format!("change: old {}, new {}", track(old_track_id), track(new_track_id)),
+		PlayerEvent::Started { ref track_id } => format!("start: {}", track(track_id)),
+		PlayerEvent::Stopped { ref track_id } => format!("stop: {}", track(track_id)),
+		PlayerEvent::Volume { volume } => format!("volume: {} ({}%)", volume, volume as u32 * 100 / 0xFFFF),
+		PlayerEvent::Seek { position } => format!("seek: {}ms", position)
+	};
+
+	if SYSLOG.load(Ordering::Relaxed) {
+		write_syslog(log::Level::Info, &format!("event: {}", details));
+	}
+	else {
+		writeln!(stderr(), "event: {}", details).unwrap();
+	}
+}
+
+// Player::new itself can't fail, but the backends panic if they can't open their output - in the player's thread, and
+// with panic = 'abort' in release builds that's the end of the process. Make sure the output is there before we start.
+fn output_writable(device: Option<&str>) -> io::Result<()> {
+	match device {
+		Some(device) => OpenOptions::new().write(true).open(device).map(|_| ()),
+		None => stdout_writable()
+	}
+}
+
+#[cfg(unix)]
+fn stdout_writable() -> io::Result<()> {
+	let flags = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_GETFL) };
+
+	if flags == -1 {
+		return Err(io::Error::last_os_error());
+	}
+
+	if flags & libc::O_ACCMODE == libc::O_RDONLY {
+		return Err(io::Error::new(io::ErrorKind::PermissionDenied, "stdout is read-only"));
+	}
+
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn stdout_writable() -> io::Result<()> {
+	Ok(())
+}
+
+// some sandboxes and containers come without a null device, or don't let us write to it
+fn null_device_writable() -> bool {
+	match output_writable(Some(NULLDEVICE)) {
+		Ok(_) => true,
+		Err(e) => {
+			writeln!(stderr(), "warning: can't write to {}: {} - discarding the audio internally", NULLDEVICE, e).unwrap();
+			false
+		}
+	}
+}
+
+// nobody's listening: --prefetch only wants the audio data in the cache, and without a null device LMS mode has nowhere else to put it
+struct NullSink;
+
+impl audio_backend::Sink for NullSink {
+	fn start(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+
+	fn stop(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+
+	fn write(&mut self, _data: &[i16]) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+// --passthrough-chunk-size: hand the data to the output in blocks of the same size, whatever librespot produces
+struct ChunkedSink {
+	sink: Box<audio_backend::Sink>,
+	// in samples - librespot passes the OGG data through in 16 bit units, too
+	chunk_size: usize,
+	buffer: Vec<i16>
+}
+
+impl audio_backend::Sink for ChunkedSink {
+	fn start(&mut self) -> io::Result<()> {
+		self.sink.start()
+	}
+
+	fn stop(&mut self) -> io::Result<()> {
+		// the end of the track doesn't need to fill a whole chunk
+		if !self.buffer.is_empty() {
+			self.sink.write(&self.buffer)?;
+			self.buffer.clear();
+		}
+
+		self.sink.stop()
+	}
+
+	fn write(&mut self, data: &[i16]) -> io::Result<()> {
+		self.buffer.extend_from_slice(data);
+
+		if self.buffer.len() >= self.chunk_size {
+			let complete = self.buffer.len() - self.buffer.len() % self.chunk_size;
+
+			for chunk in self.buffer[..complete].chunks(self.chunk_size) {
+				self.sink.write(chunk)?;
+			}
+
+			self.buffer.drain(..complete);
+		}
+
+		Ok(())
+	}
+}
+
+// tells whether any audio has gone out yet - once it has, loading the track again would put a second stream into the same pipe
+struct WrittenSink {
+	sink: Box<audio_backend::Sink>,
+	written: Arc<AtomicBool>
+}
+
+impl audio_backend::Sink for WrittenSink {
+	fn start(&mut self) -> io::Result<()> {
+		self.sink.start()
+	}
+
+	fn stop(&mut self) -> io::Result<()> {
+		self.sink.stop()
+	}
+
+	fn write(&mut self, data: &[i16]) -> io::Result<()> {
+		if !data.is_empty() {
+			self.written.store(true, Ordering::SeqCst);
+		}
+
+		self.sink.write(data)
+	}
+}
+
+// set by SIGUSR1 - log everything down to debug level, regardless of what was configured
+static DEBUG_LOGGING: AtomicBool = AtomicBool::new(false);
+
+// --syslog: send the log messages to the system logger rather than stderr
+static SYSLOG: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn open_syslog() {
+	unsafe { libc::openlog(b"spotty\0".as_ptr() as *const libc::c_char, libc::LOG_PID, libc::LOG_DAEMON) };
+	SYSLOG.store(true, Ordering::Relaxed);
+}
+
+#[cfg(not(unix))]
+fn open_syslog() {
+	writeln!(stderr(), "warning: --syslog is only supported on Unix systems").unwrap();
+}
+
+#[cfg(unix)]
+fn write_syslog(level: log::Level, message: &str) {
+	let priority = match level {
+		log::Level::Error => libc::LOG_ERR,
+		log::Level::Warn => libc::LOG_WARNING,
+		log::Level::Info => libc::LOG_INFO,
+		log::Level::Debug | log::Level::Trace => libc::LOG_DEBUG
+	};
+
+	// syslog() wants a C string - there's no room for NULs in it
+	if let Ok(message) = std::ffi::CString::new(message.replace('\0', "")) {
+		unsafe { libc::syslog(priority, b"%s\0".as_ptr() as *const libc::c_char, message.as_ptr()) };
+	}
+}
+
+#[cfg(not(unix))]
+fn write_syslog(_level: log::Level, _message: &str) {}
+
+// switches between the configured logger and a debug one at runtime
+struct ToggleLogger {
+	configured: env_logger::Logger,
+	debug: env_logger::Logger
+}
+
+impl ToggleLogger {
+	fn current(&self) -> &env_logger::Logger {
+		if DEBUG_LOGGING.load(Ordering::Relaxed) { &self.debug } else { &self.configured }
+	}
+}
+
+impl log::Log for ToggleLogger {
+	fn enabled(&self, metadata: &log::Metadata) -> bool {
+		self.current().enabled(metadata)
+	}
+
+	fn log(&self, record: &log::Record) {
+		if !SYSLOG.load(Ordering::Relaxed) {
+			return self.current().log(record);
+		}
+
+		if self.current().matches(record) {
+			write_syslog(record.level(), &format!("{}: {}", record.target(), record.args()));
+		}
+	}
+
+	fn flush(&self) {}
+}
+
+#[cfg(unix)]
+fn toggle_debug_logging() {
+	let debug = !DEBUG_LOGGING.load(Ordering::Relaxed);
+	DEBUG_LOGGING.store(debug, Ordering::Relaxed);
+	writeln!(stderr(), "Debug logging {}", if debug { "enabled" } else { "disabled" }).unwrap();
+}
+
+#[cfg(unix)]
+fn debug_logging_signal() -> IoStream<i32> {
+	Box::new(tokio_signal::unix::Signal::new(tokio_signal::unix::SIGUSR1).flatten_stream())
+}
+
+fn setup_logging(log_level: Option<String>, syslog: bool) {
+	if syslog {
+		open_syslog();
+	}
+
 	let mut builder = env_logger::Builder::new();
 	match env::var("RUST_LOG") {
 		Ok(config) => {
 			builder.parse_filters(&config);
-			builder.init();
-
-			if verbose {
-				warn!("`--verbose` flag overidden by `RUST_LOG` environment variable");
-			}
 		}
 		Err(_) => {
-			if verbose {
-				builder.parse_filters("mdns=info,librespot=debug,spotty=info");
+			if let Some(ref level) = log_level {
+				builder.parse_filters(&format!("mdns={0},librespot={0},spotty={0}", level));
 			} else {
 				builder.parse_filters("mdns=error,librespot=warn,spotty=error");
 			}
-			builder.init();
 		}
 	}
+
+	let logger = ToggleLogger {
+		configured: builder.build(),
+		debug: env_logger::Builder::new().parse_filters("mdns=debug,librespot=debug,spotty=debug").build()
+	};
+
+	let max_level = cmp::max(logger.configured.filter(), logger.debug.filter());
+	log::set_boxed_logger(Box::new(logger)).unwrap();
+	log::set_max_level(max_level);
+
+	if env::var("RUST_LOG").is_ok() && log_level.is_some() {
+		warn!("`--log-level` and `--verbose` flags overidden by `RUST_LOG` environment variable");
+	}
 }
 
 #[derive(Clone)]
@@ -104,6 +809,7 @@ struct Setup {
 	enable_discovery: bool,
 
 	authenticate: bool,
+	refresh_credentials: bool,
 
 	get_token: bool,
 	save_token: Option<String>,
@@ -111,8 +817,41 @@ struct Setup {
 	scope: Option<String>,
 
 	single_track: Option<String>,
+	metadata_header: bool,
+	passthrough_chunk_size: Option<usize>,
+	metadata_cache: Option<MetadataCache>,
+	// the cache folder to look for the files in, and the tracks to download
+	prefetch: Option<(PathBuf, Vec<String>)>,
+	probe_metadata: Option<Vec<String>>,
 	start_position: u32,
-	lms: LMS
+	repeat: bool,
+	initial_seek_retry: u32,
+	lms: LMS,
+	test_lms: bool,
+	fake_track: Option<String>,
+	event_script: Option<EventScript>,
+	print_events: bool,
+	shutdown_grace: u64,
+	discard_credentials: Option<CredentialsFile>,
+	list_devices: bool,
+	get_state: bool,
+	transfer_to: Option<String>,
+	play_context: Option<String>,
+	start_index: u32,
+	filter_explicit: bool,
+	force_ap_retry: bool,
+	connect_retry_on_auth_error: bool,
+	adaptive_bitrate: bool,
+	session_timeout: Option<u64>,
+	exit_on_idle: Option<u64>,
+	once: bool,
+	health_port: Option<u16>,
+	health_address: IpAddr,
+	reconnect_jitter: u64,
+	resume_on_reconnect: bool,
+	resume_last: Option<PathBuf>,
+	startup_json: Option<Value>,
+	pause_on_lms_failure: bool
 }
 
 fn setup(args: &[String]) -> Setup {
@@ -120,26 +859,81 @@ fn setup(args: &[String]) -> Setup {
 	opts.optopt("c", "cache", "Path to a directory where files will be cached.", "CACHE")
 		.optflag("", "enable-audio-cache", "Enable caching of the audio data.")
 		.optflag("", "disable-audio-cache", "(Only here fore compatibility with librespot - audio cache is disabled by default).")
+		.optopt("", "cache-wait", "Wait up to this many seconds for the cache folder to become available, eg. on a network share which isn't mounted yet. Defaults to 0.", "SECONDS")
+		.optopt("", "credentials-json", "Use these credentials instead of the cached ones - same format as the credentials.json file in the cache folder.", "JSON")
+		.optopt("", "open-files-limit", "Set the limit of open files (eg. audio cache files) for the process. Unix only.", "N")
+		.optflag("", "disable-credential-cache", "Don't store credentials in the cache folder, even if it's used for the audio cache.")
+		.optflag("", "cache-clear", "Remove the cached audio data from the cache folder and exit.")
+		.optflag("", "cache-clear-credentials", "Remove the cached credentials, too. Only valid with the --cache-clear option.")
 		.reqopt("n", "name", "Device name", "NAME")
-		.optopt("b", "bitrate", "Bitrate (96, 160 or 320). Defaults to 320.", "BITRATE")
+		.optopt("", "connect-name-suffix", "Text to append to the device name shown in the Spotify applications. The device ID is still based on --name only.", "SUFFIX")
+		.optopt("", "device-type", "The kind of device to show in the Spotify applications, and to tell LMS about for its icon: computer, tablet, smartphone, speaker, tv, avr, stb or audiodongle. Defaults to speaker.", "TYPE")
+		.optflag("", "force-discovery-name-unique", "Append a short hash of the host name and cache folder to the name shown in the Spotify applications, to tell several instances with the same --name apart.")
+		.optflag("", "metered", "Save data on metered connections: stream at 96 kbps and don't write the audio cache, overriding --bitrate and --enable-audio-cache.")
+		.optopt("b", "bitrate", "Bitrate (96, 160, 320 or auto). Defaults to 320. \"auto\" starts at 160, steps down whenever the connection drops within an hour, and back up when it drops after a longer run - changes apply once reconnected, best-effort only.", "BITRATE")
 		.optflag("", "pass-through", "Pass raw OGG stream to output")
-		.optopt("", "player-mac", "MAC address of the Squeezebox to be controlled", "MAC")
+		.optopt("", "passthrough-chunk-size", "Write the OGG stream in blocks of this many bytes (an even number). Only valid with the --single-track and --pass-through options.", "BYTES")
+		.optflag("", "metadata-header", "Write the track's metadata as a line of JSON before the OGG stream. Only valid with the --single-track and --pass-through options.")
+		.optflag("", "metadata-cache", "Keep track metadata in memory, and in the cache folder if --cache is given, rather than asking Spotify for it again.")
+		.optopt("", "metadata-cache-ttl", "Time (in seconds) cached track metadata is used for. Defaults to 86400 (one day). Only valid with the --metadata-cache option.", "SECONDS")
+		.optopt("", "player-mac", "MAC address of the Squeezebox to be controlled. Use a comma separated list to control several players.", "MAC")
 		.optopt("", "lms", "hostname and port of Logitech Media Server instance (eg. localhost:9000)", "LMS")
 		.optopt("", "lms-auth", "Authentication data to access Logitech Media Server", "LMSAUTH")
+		.optopt("", "lms-socket", "Path to a Unix domain socket to send Logitech Media Server notifications to instead of using TCP (Unix only)", "PATH")
+		.optopt("", "lms-batch", "Collect the notifications to Logitech Media Server for this many milliseconds, and send them as one JSON-RPC batch request.", "MS")
+		.optopt("", "lms-retry-base-ms", "Retry notifications Logitech Media Server didn't accept up to 3 times, waiting this many milliseconds before the first retry, and --lms-retry-multiplier times as long before every further one.", "MS")
+		.optopt("", "lms-retry-multiplier", "How much longer to wait before every further retry of a notification. Defaults to 3.", "FACTOR")
+		.optopt("", "event-script", "Run the given executable on every player event. Event details are passed in the PLAYER_EVENT, TRACK_ID, OLD_TRACK_ID, VOLUME and POSITION environment variables.", "PATH")
+		.optflag("", "print-events", "Print every player event with all its details on stderr, in release builds, too. For debugging the LMS integration.")
+		.optopt("", "shutdown-grace", "Time (in milliseconds) to wait for pending notifications to Logitech Media Server when shutting down. Defaults to 1000.", "MS")
+		.optopt("", "fake-track", "For testing only: play a local file to stdout instead of a Spotify track, sending the same events to LMS and the event script.", "PATH")
+		.optopt("", "on-lms-failure", "What to do if LMS can't be notified of an event: continue (default) or stop playback.", "ACTION")
+		.optopt("", "event-uri-form", "How to pass track IDs to LMS: base62 (default) or uri (spotify:track:...).", "FORM")
+		.optflag("", "test-lms", "Send a version query to the Logitech Media Server, print the result and exit. Use to verify the --lms, --player-mac and --lms-auth parameters.")
 		.optopt("", "single-track", "Play a single track ID and exit.", "ID")
+		.optopt("", "play-context", "Play the given album, artist or playlist URI as soon as we're connected, then carry on as a Spotify Connect device.", "URI")
+		.optopt("", "start-index", "Position of the track to start with in the --play-context context, counting from 0. Defaults to 0.", "N")
+		.optflag("", "filter-explicit", "Don't play tracks Spotify marks as explicit: --single-track refuses them, --play-context skips them. Relies on Spotify's labelling.")
+		.optopt("", "prefetch", "Download the given comma separated track IDs into the audio cache without playing them, and exit. Requires the --cache option.", "ID[,ID...]")
+		.optopt("", "probe-metadata-batch", "Print the metadata of the given comma separated track IDs as a JSON array, using a single session, and exit. Tracks which fail come with an error instead.", "ID[,ID...]")
 		.optopt("", "start-position", "Position (in seconds) where playback should be started. Only valid with the --single-track option.", "STARTPOSITION")
+		.optopt("", "repeat", "Repeat mode: off or track. Only valid with the --single-track option.", "MODE")
+		.optopt("", "initial-seek-retry", "Number of times to retry loading the track if it fails at the given --start-position before any audio was written. The player doesn't report where it actually started, so a load landing at the wrong position isn't detected. Defaults to 0.", "RETRIES")
 		.optflag("", "enable-volume-normalisation", "Play all tracks at the same volume")
+		.optopt("", "normalisation-method", "Volume normalisation method. Only \"track\" is supported by the bundled librespot. Defaults to track.", "METHOD")
+		.optopt("", "normalisation-pregain", "Pregain (in dB) applied by volume normalisation, between -20 and 20. Defaults to 0. The bundled librespot has no limiter to tune beyond that.", "PREGAIN")
 		.optopt("u", "username", "Username to sign in with", "USERNAME")
 		.optopt("p", "password", "Password", "PASSWORD")
 		.optflag("a", "authenticate", "Authenticate given username and password. Make sure you define a cache folder to store credentials.")
+		.optflag("", "refresh-credentials", "Sign in with the cached credentials to have Spotify issue new ones, store them in the cache folder and exit. Run this periodically to keep the cached credentials from expiring.")
+		.optopt("", "user-agent", "User agent string to present to Spotify. Defaults to the spotty version.", "USER_AGENT")
 		.optopt("", "ap-port", "Connect to AP with specified port. If no AP with that port are present fallback AP will be used. Available ports are usually 80, 443 and 4070", "AP_PORT")
-		.optflag("", "disable-discovery", "Disable discovery mode")
+		.optopt("", "health-port", "Serve the connection state as JSON on http://<host>:PORT/health, for monitoring, and the playback state (see --get-state) on http://<host>:PORT/state.", "PORT")
+		.optopt("", "health-address", "The address to serve --health-port on. Defaults to 127.0.0.1 - use 0.0.0.0 to let anybody on the network read the state of the connection and what's playing.", "ADDRESS")
+		.optflag("", "once", "Quit once the first track has been played, ie. when the player moves on to the next one. Pausing doesn't count.")
+		.optopt("", "session-timeout", "Release the Spotify session after this many minutes without playback, and tell LMS (\"idle\"). Discovery stays active to pick it up again.", "MINUTES")
+		.optopt("", "exit-on-idle", "Quit after this many seconds without playback, and tell LMS (\"exit\"). For instances started on demand.", "SECONDS")
+		.optopt("", "reconnect-jitter", "Add a random delay of up to this many milliseconds to every reconnection attempt, so several instances don't all reconnect at once.", "MS")
+		.optflag("", "resume-last", "Remember what we were playing in the cache folder, and continue there the next time we're started. Requires the --cache option.")
+		.optflag("", "resume-on-reconnect", "If the connection drops during playback, continue the track where it was interrupted once we're reconnected, rather than stopping.")
+		.optflag("", "connect-retry-on-auth-error", "Keep trying to connect if Spotify rejects the credentials, rather than giving up on them right away.")
+		.optflag("", "force-ap-retry", "After repeated connection failures, try access points on a different port (4070, 443, 80) instead of retrying the same one.")
+		.optflag("", "disable-discovery", "Disable discovery mode. Together with cached or given credentials this runs spotty as a headless Spotify Connect device.")
 		.optflag("t", "get-token", "Get oauth token to be used with the web API etc. and print it to the console.")
 		.optopt("T", "save-token", "Get oauth token to be used with the web API etc. and store it in the given file.", "TOKENFILE")
 		.optopt("i", "client-id", "A Spotify client_id to be used to get the oauth token. Required with the --get-token request.", "CLIENT_ID")
 		.optopt("", "scope", "The scopes you want to have access to with the oauth token.", "SCOPE")
 		.optflag("x", "check", "Run quick internal check")
-		.optflag("v", "verbose", "Enable verbose output");
+		.optopt("", "check-output", "Also write the capabilities reported by --check to this file, as plain JSON.", "PATH")
+		.optflag("", "list-devices", "List the Spotify Connect devices of the account as JSON and exit.")
+		.optflag("", "get-state", "Print the playback state of the active Spotify Connect device as JSON and exit.")
+		.optopt("", "transfer-to", "Transfer the active playback to the Spotify Connect device with the given ID (see --list-devices and --print-device-id) and exit.", "DEVICE_ID")
+		.optflag("", "print-device-id", "Print the Spotify device ID derived from the device name and exit.")
+		.optflag("", "startup-json", "Print a line of JSON with version, capabilities, process ID and mode on startup (on stderr when streaming audio).")
+		.optflag("", "dump-config", "Print the effective configuration as JSON (without credentials) and exit.")
+		.optopt("", "log-level", "Log level: error, warn, info, debug or trace. On Unix systems SIGUSR1 toggles debug logging at runtime.", "LEVEL")
+		.optflag("", "syslog", "Send the log messages, and the events of --print-events, to the system logger instead of stderr. Unix only.")
+		.optflag("v", "verbose", "Enable verbose output - same as --log-level info.");
 
 	let matches = match opts.parse(&args[1..]) {
 		Ok(m) => m,
@@ -152,36 +946,146 @@ fn setup(args: &[String]) -> Setup {
 	if matches.opt_present("check") {
 		println!("ok {}", VERSION.to_string());
 
-		let capabilities = json!({
-			"version": env!("CARGO_PKG_VERSION").to_string(),
-			"lms-auth": true,
-			"volume-normalisation": true,
-			"debug": DEBUGMODE,
-			"ogg-direct": true,
-			"save-token": true,
-			"podcasts": true
-		});
+		let capabilities = capabilities(&opts);
 
 		println!("{}", capabilities.to_string());
+
+		// tools only interested in the capabilities don't need to parse the version line
+		if let Some(path) = matches.opt_str("check-output") {
+			if let Err(e) = File::create(&path).and_then(|mut file| file.write_all(capabilities.to_string().as_bytes())) {
+				writeln!(stderr(), "error: can't write capabilities to {}: {}", path, e).unwrap();
+			}
+		}
+
 		exit(1);
 	}
 
-	#[cfg(debug_assertions)]
-	{
-		let verbose = matches.opt_present("verbose");
-		setup_logging(verbose);
+	if matches.opt_present("cache-clear") {
+		match matches.opt_str("c") {
+			Some(cache_location) => {
+				let removed = clear_cache(Path::new(&cache_location), matches.opt_present("cache-clear-credentials"));
+				println!("{}", json!({ "removed": removed }).to_string());
+				exit(0);
+			}
+			None => {
+				writeln!(stderr(), "error: --cache-clear requires the --cache option").unwrap();
+				exit(1);
+			}
+		}
+	}
+
+	for &(option, required) in OPTION_DEPENDENCIES {
+		if matches.opt_present(option) && !matches.opt_present(required) {
+			writeln!(stderr(), "error: --{} is only valid with the --{} option", option, required).unwrap();
+			exit(1);
+		}
 	}
 
+	let log_level = match matches.opt_str("log-level") {
+		Some(level) => match level.trim().to_lowercase().as_str() {
+			level @ "error" | level @ "warn" | level @ "info" | level @ "debug" | level @ "trace" => Some(level.to_string()),
+			_ => {
+				writeln!(stderr(), "error: invalid log level '{}' - use error, warn, info, debug or trace", level).unwrap();
+				exit(1);
+			}
+		},
+		None if matches.opt_present("verbose") => Some("info".to_string()),
+		None => None
+	};
+
+	setup_logging(log_level, matches.opt_present("syslog"));
+
 	let name = matches.opt_str("name").unwrap();
 
-	let use_audio_cache = matches.opt_present("enable-audio-cache") && !matches.opt_present("disable-audio-cache");
+	if matches.opt_present("print-device-id") {
+		println!("{}", device_id(&name));
+		exit(0);
+	}
+
+	// on a metered connection we use as little data as we can, whatever else was asked for
+	let metered = matches.opt_present("metered");
+
+	let prefetch = matches.opt_str("prefetch").map(|ids| {
+		if metered {
+			writeln!(stderr(), "error: --prefetch can't be used with --metered").unwrap();
+			exit(1);
+		}
+
+		let cache_location = matches.opt_str("c").unwrap_or_else(|| {
+			writeln!(stderr(), "error: --prefetch requires the --cache option").unwrap();
+			exit(1);
+		});
+
+		(PathBuf::from(cache_location), ids.split(',').map(|id| id.trim().to_string()).filter(|id| !id.is_empty()).collect::<Vec<String>>())
+	});
+
+	let probe_metadata = matches.opt_str("probe-metadata-batch")
+		.map(|ids| ids.split(',').map(|id| id.trim().to_string()).filter(|id| !id.is_empty()).collect::<Vec<String>>());
+
+	// there's no point in prefetching without the audio cache
+	let use_audio_cache = !metered && (prefetch.is_some() || (matches.opt_present("enable-audio-cache") && !matches.opt_present("disable-audio-cache")));
+
+	if let Some(limit) = matches.opt_str("open-files-limit") {
+		match limit.parse::<u64>() {
+			Ok(limit) if limit > 0 => set_open_files_limit(limit),
+			_ => {
+				writeln!(stderr(), "error: invalid open files limit '{}'", limit).unwrap();
+				exit(1);
+			}
+		}
+	}
+
+	let cache_wait = matches.opt_str("cache-wait")
+		.map(|seconds| seconds.parse::<u64>().unwrap_or_else(|_| {
+			writeln!(stderr(), "error: invalid cache wait time '{}'", seconds).unwrap();
+			exit(1);
+		}))
+		.unwrap_or(0);
+
+	if let Some(ref cache_location) = matches.opt_str("c") {
+		wait_for_cache(Path::new(cache_location), Duration::from_secs(cache_wait));
+	}
 
 	let cache = matches.opt_str("c").map(|cache_location| {
 		Cache::new(PathBuf::from(cache_location), use_audio_cache)
 	});
 
+	let disable_credential_cache = matches.opt_present("disable-credential-cache");
+
+	let refresh_credentials = matches.opt_present("refresh-credentials");
+
+	// the fresh credentials need to go somewhere
+	if refresh_credentials && (matches.opt_str("c").is_none() || disable_credential_cache) {
+		writeln!(stderr(), "error: --refresh-credentials requires the --cache option, and can't be used with --disable-credential-cache").unwrap();
+		exit(1);
+	}
+
+	if disable_credential_cache && matches.opt_present("authenticate") {
+		writeln!(stderr(), "error: --authenticate needs to store the credentials - it can't be used with --disable-credential-cache").unwrap();
+		exit(1);
+	}
+
+	let discard_credentials = if disable_credential_cache {
+		matches.opt_str("c").map(|cache_location| CredentialsFile::new(PathBuf::from(cache_location).join("credentials.json")))
+	} else {
+		None
+	};
+
 	let credentials = {
-		let cached_credentials = cache.as_ref().and_then(Cache::credentials);
+		let inline_credentials = matches.opt_str("credentials-json").map(|data| {
+			parse_credentials_json(&data).unwrap_or_else(|| {
+				writeln!(stderr(), "error: invalid --credentials-json - expected {{\"username\":\"...\",\"auth_type\":1,\"auth_data\":\"...\"}}").unwrap();
+				exit(1);
+			})
+		});
+
+		let cached_credentials = if inline_credentials.is_some() {
+			inline_credentials
+		} else if disable_credential_cache {
+			None
+		} else {
+			cache.as_ref().and_then(Cache::credentials)
+		};
 
 		let password = |username: &String| -> String {
 			write!(stderr(), "Password for {}: ", username).unwrap();
@@ -202,14 +1106,101 @@ fn setup(args: &[String]) -> Setup {
 	let enable_discovery = !matches.opt_present("disable-discovery");
 
 	let start_position = matches.opt_str("start-position")
-		.unwrap_or("0".to_string())
-		.parse::<f32>().unwrap_or(0.0);
+		.map(|position| match position.parse::<f32>() {
+			Ok(position) if position >= 0.0 => position,
+			_ => {
+				writeln!(stderr(), "error: invalid start position '{}' - expected a number of seconds", position).unwrap();
+				exit(1);
+			}
+		})
+		.unwrap_or(0.0);
+
+	let user_agent = matches.opt_str("user-agent").unwrap_or(VERSION.to_string());
+	if user_agent.trim().is_empty() {
+		writeln!(stderr(), "error: --user-agent must not be empty").unwrap();
+		exit(1);
+	}
+
+	let repeat = match matches.opt_str("repeat") {
+		Some(ref mode) if mode == "track" => true,
+		Some(ref mode) if mode == "off" => false,
+		Some(mode) => {
+			writeln!(stderr(), "error: invalid repeat mode '{}' - use off or track", mode).unwrap();
+			exit(1);
+		}
+		None => false
+	};
+
+	if repeat && !matches.opt_present("single-track") {
+		writeln!(stderr(), "error: --repeat is only valid with the --single-track option").unwrap();
+		exit(1);
+	}
+
+	let session_timeout = matches.opt_str("session-timeout")
+		.map(|minutes| minutes.parse::<u64>().ok().filter(|minutes| *minutes > 0).unwrap_or_else(|| {
+			writeln!(stderr(), "error: invalid session timeout '{}' - expected a number of minutes", minutes).unwrap();
+			exit(1);
+		}));
+
+	// without discovery nobody could wake us up again
+	if session_timeout.is_some() && matches.opt_present("disable-discovery") {
+		writeln!(stderr(), "error: --session-timeout can't be used with --disable-discovery").unwrap();
+		exit(1);
+	}
+
+	let exit_on_idle = matches.opt_str("exit-on-idle")
+		.map(|seconds| match seconds.parse::<u64>() {
+			Ok(seconds) if seconds > 0 => seconds,
+			_ => {
+				writeln!(stderr(), "error: invalid idle time '{}'", seconds).unwrap();
+				exit(1);
+			}
+		});
+
+	let reconnect_jitter = matches.opt_str("reconnect-jitter")
+		.map(|jitter| jitter.parse::<u64>().unwrap_or_else(|_| {
+			writeln!(stderr(), "error: invalid reconnect jitter '{}'", jitter).unwrap();
+			exit(1);
+		}))
+		.unwrap_or(0);
+
+	let resume_last = if matches.opt_present("resume-last") {
+		match matches.opt_str("c") {
+			Some(cache_location) => Some(Path::new(&cache_location).join(LAST_PLAYED_FILE)),
+			None => {
+				writeln!(stderr(), "error: --resume-last requires the --cache option").unwrap();
+				exit(1);
+			}
+		}
+	} else {
+		None
+	};
+
+	let health_port = matches.opt_str("health-port")
+		.map(|port| port.parse::<u16>().unwrap_or_else(|_| {
+			writeln!(stderr(), "error: invalid health port '{}'", port).unwrap();
+			exit(1);
+		}));
+
+	// there's no authentication - only local monitoring tools get to see it, unless asked otherwise
+	let health_address = matches.opt_str("health-address")
+		.map_or(IpAddr::from([127, 0, 0, 1]), |address| address.trim().parse::<IpAddr>().unwrap_or_else(|_| {
+			writeln!(stderr(), "error: invalid health address '{}' - expected eg. 127.0.0.1", address).unwrap();
+			exit(1);
+		}));
+
+	let initial_seek_retry = matches.opt_str("initial-seek-retry")
+		.map(|retries| retries.parse::<u32>().unwrap_or_else(|_| {
+			writeln!(stderr(), "error: invalid number of retries '{}'", retries).unwrap();
+			exit(1);
+		}))
+		.unwrap_or(0);
 
 	let session_config = {
 		let device_id = device_id(&name);
 
 		SessionConfig {
-			user_agent: VERSION.to_string(),
+			user_agent: user_agent,
 			device_id: device_id,
 			proxy: None,
 			ap_port: matches
@@ -220,56 +1211,445 @@ fn setup(args: &[String]) -> Setup {
 
 	let pass_through = matches.opt_present("pass-through");
 
+	let adaptive_bitrate = !metered && matches.opt_str("b").map_or(false, |bitrate| is_adaptive_bitrate(&bitrate));
+
+	let metadata_header = matches.opt_present("metadata-header");
+	if metadata_header && !(pass_through && matches.opt_present("single-track")) {
+		writeln!(stderr(), "error: --metadata-header is only valid with the --single-track and --pass-through options").unwrap();
+		exit(1);
+	}
+
+	let passthrough_chunk_size = matches.opt_str("passthrough-chunk-size").map(|size| {
+		if !(pass_through && matches.opt_present("single-track")) {
+			writeln!(stderr(), "error: --passthrough-chunk-size is only valid with the --single-track and --pass-through options").unwrap();
+			exit(1);
+		}
+
+		match size.parse::<usize>() {
+			Ok(size) if size > 0 && size % 2 == 0 => size,
+			_ => {
+				writeln!(stderr(), "error: invalid chunk size '{}' - expected an even number of bytes", size).unwrap();
+				exit(1);
+			}
+		}
+	});
+
+	let metadata_cache_ttl = matches.opt_str("metadata-cache-ttl")
+		.map(|ttl| ttl.parse::<u64>().unwrap_or_else(|_| {
+			writeln!(stderr(), "error: invalid metadata cache TTL '{}'", ttl).unwrap();
+			exit(1);
+		}));
+
+	let metadata_cache = if matches.opt_present("metadata-cache") {
+		Some(MetadataCache::new(
+			matches.opt_str("c").map(|cache_location| PathBuf::from(cache_location).join("metadata")),
+			Duration::from_secs(metadata_cache_ttl.unwrap_or(METADATA_CACHE_TTL))
+		))
+	} else {
+		None
+	};
+
 	let player_config = {
-		let bitrate = matches.opt_str("b").as_ref()
-				.map(|bitrate| Bitrate::from_str(bitrate).expect("Invalid bitrate"))
-				.unwrap_or(Bitrate::Bitrate320);
+		// start conservatively if we're supposed to find the bitrate ourselves
+		let bitrate = if metered {
+			Bitrate::Bitrate96
+		} else {
+			matches.opt_str("b").as_ref()
+				.map(|bitrate| if adaptive_bitrate { Bitrate::Bitrate160 } else { parse_bitrate(bitrate) })
+				.unwrap_or(Bitrate::Bitrate320)
+		};
+
+		let normalisation = matches.opt_present("enable-volume-normalisation");
+
+		if let Some(method) = matches.opt_str("normalisation-method") {
+			match method.trim().to_lowercase().as_str() {
+				// librespot applies the track gain - nothing to configure
+				"track" => (),
+				"album" => {
+					writeln!(stderr(), "error: album normalisation is not supported by this build - use \"track\"").unwrap();
+					exit(1);
+				}
+				_ => {
+					writeln!(stderr(), "error: invalid normalisation method '{}' - use \"track\"", method).unwrap();
+					exit(1);
+				}
+			}
+
+			if !normalisation {
+				writeln!(stderr(), "warning: --normalisation-method has no effect without --enable-volume-normalisation").unwrap();
+			}
+		}
+
+		let normalisation_pregain = match matches.opt_str("normalisation-pregain") {
+			Some(pregain) => {
+				let pregain = match pregain.trim().parse::<f32>() {
+					Ok(value) if value >= -20.0 && value <= 20.0 => value,
+					_ => {
+						writeln!(stderr(), "error: invalid normalisation pregain '{}' - expected a value between -20 and 20 (dB)", pregain).unwrap();
+						exit(1);
+					}
+				};
+
+				if !normalisation {
+					writeln!(stderr(), "warning: --normalisation-pregain has no effect without --enable-volume-normalisation").unwrap();
+				}
+
+				pregain
+			}
+			None => PlayerConfig::default().normalisation_pregain
+		};
 
 		PlayerConfig {
 			bitrate: bitrate,
-			normalisation: matches.opt_present("enable-volume-normalisation"),
-			normalisation_pregain: PlayerConfig::default().normalisation_pregain,
+			normalisation: normalisation,
+			normalisation_pregain: normalisation_pregain,
 			pass_through: pass_through,
 			lms_connect_mode: !matches.opt_present("single-track")
 		}
 	};
 
 	let connect_config = {
+		let display_name = match matches.opt_str("connect-name-suffix") {
+			Some(ref suffix) if !suffix.trim().is_empty() => format!("{} {}", name, suffix.trim()),
+			_ => name
+		};
+
+		let display_name = if matches.opt_present("force-discovery-name-unique") {
+			format!("{} ({})", display_name, instance_suffix(matches.opt_str("c").as_ref().map(|cache| cache.as_str())))
+		} else {
+			display_name
+		};
+
+		let device_type = match matches.opt_str("device-type") {
+			Some(device_type) => device_type.trim().to_lowercase().parse::<DeviceType>().unwrap_or_else(|_| {
+				writeln!(stderr(), "error: invalid device type '{}' - use computer, tablet, smartphone, speaker, tv, avr, stb or audiodongle", device_type).unwrap();
+				exit(1);
+			}),
+			None => DeviceType::Speaker
+		};
+
 		ConnectConfig {
-			name: name,
-			device_type: DeviceType::Speaker,
+			name: display_name,
+			device_type: device_type,
 			volume: 0x8000 as u16,
 			linear_volume: true
 		}
 	};
 
-	let client_id = matches.opt_str("client-id")
-		.unwrap_or(format!("{}", include_str!("client_id.txt")));
+	let client_id = resolve_client_id(matches.opt_str("client-id"), include_str!("client_id.txt"));
 
 	let save_token = matches.opt_str("save-token").unwrap_or("".to_string());
 
-	let lms = LMS::new(matches.opt_str("lms"), matches.opt_str("player-mac"), matches.opt_str("lms-auth"));
+	let shutdown_grace = matches.opt_str("shutdown-grace")
+		.map(|grace| grace.parse::<u64>().unwrap_or_else(|_| {
+			writeln!(stderr(), "error: invalid shutdown grace period '{}'", grace).unwrap();
+			exit(1);
+		}))
+		.unwrap_or(1000);
+
+	let event_uri_form = match matches.opt_str("event-uri-form") {
+		Some(ref form) if form == "uri" => true,
+		Some(ref form) if form == "base62" => false,
+		Some(form) => {
+			writeln!(stderr(), "error: invalid event URI form '{}' - use base62 or uri", form).unwrap();
+			exit(1);
+		}
+		None => false
+	};
+
+	let pause_on_lms_failure = match matches.opt_str("on-lms-failure") {
+		Some(ref action) if action == "stop" => true,
+		Some(ref action) if action == "continue" => false,
+		Some(action) => {
+			writeln!(stderr(), "error: invalid action on LMS failure '{}' - use continue or stop", action).unwrap();
+			exit(1);
+		}
+		None => false
+	};
+
+	let play_context = matches.opt_str("play-context").map(|uri| uri.trim().to_string());
+
+	if let Some(ref uri) = play_context {
+		if !remote::is_context_uri(uri) {
+			writeln!(stderr(), "error: invalid context URI '{}' - expected an album, artist or playlist (eg. spotify:album:...)", uri).unwrap();
+			exit(1);
+		}
+
+		if matches.opt_present("single-track") {
+			writeln!(stderr(), "error: --play-context can't be used with --single-track").unwrap();
+			exit(1);
+		}
+	}
+
+	let start_index = matches.opt_str("start-index")
+		.map(|index| index.parse::<u32>().unwrap_or_else(|_| {
+			writeln!(stderr(), "error: invalid start index '{}'", index).unwrap();
+			exit(1);
+		}));
+
+	let lms_batch = matches.opt_str("lms-batch")
+		.map(|batch| batch.parse::<u64>().unwrap_or_else(|_| {
+			writeln!(stderr(), "error: invalid LMS batch window '{}'", batch).unwrap();
+			exit(1);
+		}));
 
-	Setup {
+	let mut lms = LMS::new(matches.opt_str("lms"), matches.opt_str("player-mac"), matches.opt_str("lms-auth"), matches.opt_str("lms-socket"), event_uri_form, pass_through, lms_batch);
+
+	let lms_retry_multiplier = matches.opt_str("lms-retry-multiplier")
+		.map_or(lms::RETRY_MULTIPLIER, |multiplier| match multiplier.parse::<u64>() {
+			Ok(multiplier) if multiplier > 0 => multiplier,
+			_ => {
+				writeln!(stderr(), "error: invalid LMS retry multiplier '{}'", multiplier).unwrap();
+				exit(1);
+			}
+		});
+
+	if let Some(base) = matches.opt_str("lms-retry-base-ms") {
+		match base.parse::<u64>() {
+			Ok(base) if base > 0 => lms.retry_with_backoff(base, lms_retry_multiplier),
+			_ => {
+				writeln!(stderr(), "error: invalid LMS retry delay '{}'", base).unwrap();
+				exit(1);
+			}
+		}
+	}
+
+	let setup = Setup {
 		cache: cache,
 		session_config: session_config,
 		player_config: player_config,
 		connect_config: connect_config,
 		credentials: credentials,
 		authenticate: authenticate,
+		refresh_credentials: refresh_credentials,
 		enable_discovery: enable_discovery,
 
 		get_token: matches.opt_present("get-token") || save_token.as_str().len() != 0,
 		save_token: if save_token.as_str().len() == 0 { None } else { Some(save_token) },
 
-		client_id: if client_id.as_str().len() == 0 { None } else { Some(client_id) },
+		client_id: client_id,
 		scope: matches.opt_str("scope"),
 
 		single_track: matches.opt_str("single-track"),
+		metadata_header: metadata_header,
+		passthrough_chunk_size: passthrough_chunk_size,
+		metadata_cache: metadata_cache,
+		prefetch: prefetch,
+		probe_metadata: probe_metadata,
 		start_position: (start_position * 1000.0) as u32,
+		repeat: repeat,
+		initial_seek_retry: initial_seek_retry,
+
+		lms: lms,
+		test_lms: matches.opt_present("test-lms"),
+		fake_track: matches.opt_str("fake-track"),
+		event_script: matches.opt_str("event-script").map(EventScript::new),
+		print_events: matches.opt_present("print-events"),
+		shutdown_grace: shutdown_grace,
+		discard_credentials: discard_credentials,
+		list_devices: matches.opt_present("list-devices"),
+		get_state: matches.opt_present("get-state"),
+		transfer_to: matches.opt_str("transfer-to"),
+		play_context: play_context,
+		start_index: start_index.unwrap_or(0),
+		filter_explicit: matches.opt_present("filter-explicit"),
+		force_ap_retry: matches.opt_present("force-ap-retry"),
+		connect_retry_on_auth_error: matches.opt_present("connect-retry-on-auth-error"),
+		adaptive_bitrate: adaptive_bitrate,
+		session_timeout: session_timeout,
+		exit_on_idle: exit_on_idle,
+		once: matches.opt_present("once"),
+		health_port: health_port,
+		health_address: health_address,
+		reconnect_jitter: reconnect_jitter,
+		resume_on_reconnect: matches.opt_present("resume-on-reconnect"),
+		resume_last: resume_last,
+		startup_json: if matches.opt_present("startup-json") { Some(capabilities(&opts)) } else { None },
+		pause_on_lms_failure: pause_on_lms_failure
+	};
 
-		lms: lms
+	if matches.opt_present("dump-config") {
+		println!("{}", config_json(&setup, matches.opt_str("c")).to_string());
+		exit(0);
 	}
+
+	setup
+}
+
+// what we're going to run with, for bug reports - keep anything secret out of it
+fn config_json(setup: &Setup, cache_location: Option<String>) -> Value {
+	let redacted = |value: &Option<String>| value.as_ref().map(|_| "<redacted>");
+
+	json!({
+		"version": VERSION.to_string(),
+		"cache": cache_location,
+		"credentials": setup.credentials.as_ref().map(|credentials| json!({
+			"username": credentials.username,
+			"auth_data": "<redacted>"
+		})),
+		"discard_credentials": setup.discard_credentials.is_some(),
+		"session": {
+			"user_agent": setup.session_config.user_agent,
+			"device_id": setup.session_config.device_id,
+			// a proxy URL might carry a username and password
+			"proxy": setup.session_config.proxy.as_ref().map(|proxy| if proxy.contains('@') { "<redacted>".to_string() } else { proxy.clone() }),
+			"ap_port": setup.session_config.ap_port,
+			"force_ap_retry": setup.force_ap_retry,
+			"connect_retry_on_auth_error": setup.connect_retry_on_auth_error,
+			"reconnect_jitter": setup.reconnect_jitter,
+			"resume_on_reconnect": setup.resume_on_reconnect
+		},
+		"resume_last": setup.resume_last.is_some(),
+		"player": {
+			"bitrate": format!("{:?}", setup.player_config.bitrate),
+			"adaptive_bitrate": setup.adaptive_bitrate,
+			"normalisation": setup.player_config.normalisation,
+			"normalisation_pregain": setup.player_config.normalisation_pregain,
+			"pass_through": setup.player_config.pass_through
+		},
+		"connect": {
+			"name": setup.connect_config.name,
+			"device_type": setup.connect_config.device_type.to_string(),
+			"volume": setup.connect_config.volume,
+			"discovery": setup.enable_discovery,
+			"session_timeout": setup.session_timeout,
+			"exit_on_idle": setup.exit_on_idle
+		},
+		"lms": setup.lms.config_json(),
+		"event_script": setup.event_script.as_ref().map(|script| script.path()),
+		"shutdown_grace": setup.shutdown_grace,
+		"client_id": redacted(&setup.client_id),
+		"scope": setup.scope,
+		"save_token": setup.save_token,
+		"single_track": setup.single_track,
+		"play_context": setup.play_context,
+		"start_index": setup.start_index,
+		"filter_explicit": setup.filter_explicit,
+		"metadata_cache_ttl": setup.metadata_cache.as_ref().map(|cache| cache.ttl().as_secs()),
+		"start_position": setup.start_position,
+		"repeat": setup.repeat
+	})
+}
+
+// resolve the context and have our own Spotify Connect device play it - like a Spotify application would ask it to
+fn load_context(session: &Session, target: &str, context_uri: String, index: u32, window_size: usize, filter_explicit: bool) -> Box<Future<Item=(Context, Vec<SpotifyId>), Error=&'static str>> {
+	let session = session.clone();
+	let target = target.to_string();
+
+	let load = remote::context(&session, &context_uri)
+		.map_err(|_| "request_failed")
+		.and_then(move |context| load_window(&session, &target, context, index, window_size, filter_explicit, 0));
+
+	Box::new(load)
+}
+
+// hand the window of window_size tracks containing the track at index over to the player, fetching as many pages as needed.
+// Returns what's left of the context, and the tracks handed over.
+fn load_window(session: &Session, target: &str, context: Context, index: u32, window_size: usize, filter_explicit: bool, position: u32) -> Box<Future<Item=(Context, Vec<SpotifyId>), Error=&'static str>> {
+	let index = index as usize;
+	let window_start = index - index % window_size;
+
+	let filter_session = session.clone();
+	let sender = session.clone();
+	// spirc ignores frames sent in its own name
+	let ident = device_id(&format!("{} remote", target));
+	let target = target.to_string();
+
+	let load = remote::more_until(session, context, move |context| context.len() >= window_start + window_size)
+		.map_err(|_| "request_failed")
+		.and_then(move |mut context| {
+			let window = match context.window(index, window_size) {
+				Ok(window) => window,
+				// rather tell than silently start somewhere else - the caller probably has an outdated idea of the context
+				Err("invalid_start_index") => {
+					writeln!(stderr(), "error: --start-index {} is out of range - the context has {} tracks", index, context.len()).unwrap();
+					return future::Either::A(future::err("invalid_start_index"));
+				}
+				Err(e) => return future::Either::A(future::err(e))
+			};
+
+			let window = if filter_explicit {
+				without_explicit(&filter_session, window, index - window_start)
+			}
+			else {
+				Box::new(future::ok((window, index - window_start)))
+			};
+
+			future::Either::B(window.map(move |(window, index)| (context, window, index)))
+		})
+		.and_then(move |(context, window, index)| {
+			// everything in this window was explicit - we don't go looking for the next one
+			if window.is_empty() {
+				return future::Either::A(future::err("empty_context"));
+			}
+
+			info!("Playing {} tracks of {}, {} more in memory", window.len(), context.uri, context.len());
+
+			let load = remote::load(&sender, ident, target, &context.uri, &window, index as u32, position);
+			future::Either::B(load.map_err(|_| "request_failed").map(move |_| (context, window)))
+		});
+
+	Box::new(load)
+}
+
+// --resume-last: the context, track and position save_last_played() left us
+fn read_last_played(path: &Path) -> Option<(Option<String>, SpotifyId, u32)> {
+	let last_played = File::open(path).ok().and_then(|file| serde_json::from_reader::<_, Value>(file).ok())?;
+
+	let track = last_played.get("track").and_then(|track| track.as_str()).and_then(|uri| SpotifyId::from_uri(uri).ok())?;
+	let position = last_played.get("position").and_then(|position| position.as_u64()).unwrap_or(0) as u32;
+	let context = last_played.get("context").and_then(|context| context.as_str()).map(|context| context.to_string());
+
+	Some((context, track, position))
+}
+
+// --resume-last: find the track in its context again, and continue from there. If the track isn't part of the context
+// any more, or if the context was started from the Spotify applications while we weren't looking, play the track on its own.
+fn resume_context(session: &Session, target: &str, context_uri: String, track: SpotifyId, position: u32, window_size: usize, filter_explicit: bool) -> Box<Future<Item=(Context, Vec<SpotifyId>), Error=&'static str>> {
+	let pages_session = session.clone();
+	let session = session.clone();
+	let target = target.to_string();
+
+	let resume = remote::context(&pages_session, &context_uri)
+		.and_then(move |context| remote::more_until(&pages_session, context, move |context| context.index_of(track).is_some()))
+		.map_err(|_| "request_failed")
+		.and_then(move |context| match context.index_of(track) {
+			Some(index) => load_window(&session, &target, context, index as u32, window_size, filter_explicit, position),
+			None => Box::new(resume_track(&session, &target, track, position)
+				.map_err(|_| "request_failed")
+				.map(move |_| (context, vec![track])))
+		});
+
+	Box::new(resume)
+}
+
+// --resume-on-reconnect: have the new spirc continue the track where the old one was interrupted. The rest of the queue is lost.
+fn resume_track(session: &Session, target: &str, track: SpotifyId, position: u32) -> Box<Future<Item=(), Error=MercuryError>> {
+	let ident = device_id(&format!("{} remote", target));
+	remote::load(session, ident, target.to_string(), &track.to_uri(), &[track], 0, position)
+}
+
+// --filter-explicit: drop the tracks Spotify marks as explicit, and find the track to start with among the others
+fn without_explicit(session: &Session, tracks: Vec<SpotifyId>, index: usize) -> Box<Future<Item=(Vec<SpotifyId>, usize), Error=&'static str>> {
+	let flags = future::join_all(tracks.iter().map(|track| metadata::is_explicit(session, *track)).collect::<Vec<_>>());
+
+	let tracks = flags
+		.map_err(|_| "request_failed")
+		.map(move |flags| {
+			// if the track to start with is explicit, start with the next one which isn't
+			let index = flags[..index].iter().filter(|explicit| !**explicit).count();
+
+			let tracks: Vec<SpotifyId> = tracks.into_iter()
+				.zip(flags)
+				.filter(|&(_, explicit)| !explicit)
+				.map(|(track, _)| track)
+				.collect();
+
+			let index = cmp::min(index, tracks.len().saturating_sub(1));
+			(tracks, index)
+		});
+
+	Box::new(tracks)
 }
 
 struct Main {
@@ -280,21 +1660,83 @@ struct Main {
 	handle: Handle,
 
 	discovery: Option<DiscoveryStream>,
+	discovery_retry: Option<Timeout>,
+	discovery_attempts: u32,
 	signal: IoStream<()>,
+	#[cfg(unix)]
+	debug_logging_signal: IoStream<i32>,
 
 	spirc: Option<Spirc>,
 	spirc_task: Option<SpircTask>,
+	// --play-context: what to load once we're connected, the request doing it,
+	// the tracks not handed over to the player yet, and the first and last track of those which were
+	play_context: Option<String>,
+	start_index: u32,
+	filter_explicit: bool,
+	context_load: Option<Box<Future<Item=(Context, Vec<SpotifyId>), Error=&'static str>>>,
+	context: Option<Context>,
+	context_window: Option<(SpotifyId, SpotifyId)>,
 	connect: Box<Future<Item=Session, Error=io::Error>>,
+	last_credentials: Option<Credentials>,
+	reconnect_timer: Option<Timeout>,
+	reconnect_attempts: u32,
+	reconnect_jitter: u64,
+	force_ap_retry: bool,
+	// --connect-retry-on-auth-error: treat rejected credentials like any other connection failure
+	retry_auth_errors: bool,
+	adaptive_bitrate: bool,
+	connected_since: Option<Instant>,
+
+	// --resume-on-reconnect: what's playing, from the player's events, and where to continue after a reconnect
+	resume_on_reconnect: bool,
+	now_playing: Option<SpotifyId>,
+	position: Position,
+	resume: Option<(SpotifyId, u32)>,
+	resume_load: Option<Box<Future<Item=(), Error=MercuryError>>>,
+
+	// --resume-last: where to keep what's playing, what we found there at startup, and the context we've started -
+	// the player doesn't tell us about the contexts started from the Spotify applications
+	resume_last: Option<PathBuf>,
+	last_played: Option<(Option<String>, SpotifyId, u32)>,
+	playing_context: Option<String>,
+	resuming_last: bool,
+
+	session: Option<Session>,
+	session_timeout: Option<Duration>,
+	idle_timer: Option<Timeout>,
+	// --exit-on-idle: quit rather than only release the session
+	exit_on_idle: Option<Duration>,
+	exit_timer: Option<Timeout>,
+	idle: bool,
+
+	// --once: quit when the first track is done
+	once: bool,
+	played: bool,
+
+	health: Rc<RefCell<Health>>,
 
 	shutdown: bool,
+	shutdown_grace: Duration,
+	shutdown_deadline: Option<Instant>,
+	shutdown_timer: Option<Timeout>,
 	authenticate: bool,
 
+	// the audio goes nowhere either way - if the null device isn't there for us, don't even try the backend
+	null_sink: bool,
 	event_channel: Option<UnboundedReceiver<PlayerEvent>>,
-	lms: LMS
+	lms: LMS,
+	lms_failures: Option<UnboundedReceiver<()>>,
+	event_script: Option<EventScript>,
+	print_events: bool,
+	discard_credentials: Option<CredentialsFile>
 }
 
 impl Main {
 	fn new(handle: Handle, setup: Setup) -> Main {
+		let mut lms = setup.lms;
+		let lms_failures = if setup.pause_on_lms_failure { Some(lms.failures()) } else { None };
+		let null_sink = !setup.authenticate && !null_device_writable();
+
 		let mut task = Main {
 			handle: handle.clone(),
 			cache: setup.cache,
@@ -304,35 +1746,244 @@ impl Main {
 
 			connect: Box::new(futures::future::empty()),
 			discovery: None,
+			discovery_retry: None,
+			discovery_attempts: 0,
 			spirc: None,
 			spirc_task: None,
+			play_context: setup.play_context,
+			start_index: setup.start_index,
+			filter_explicit: setup.filter_explicit,
+			context_load: None,
+			context: None,
+			context_window: None,
+			last_credentials: None,
+			reconnect_timer: None,
+			reconnect_attempts: 0,
+			reconnect_jitter: setup.reconnect_jitter,
+			force_ap_retry: setup.force_ap_retry,
+			retry_auth_errors: setup.connect_retry_on_auth_error,
+			adaptive_bitrate: setup.adaptive_bitrate,
+			connected_since: None,
+
+			resume_on_reconnect: setup.resume_on_reconnect,
+			now_playing: None,
+			position: Position::new(),
+			resume: None,
+			resume_load: None,
+
+			last_played: setup.resume_last.as_ref().and_then(|path| read_last_played(path)),
+			resume_last: setup.resume_last,
+			playing_context: None,
+			resuming_last: false,
+
+			session: None,
+			session_timeout: setup.session_timeout.map(|minutes| Duration::from_secs(minutes * 60)),
+			idle_timer: None,
+			exit_on_idle: setup.exit_on_idle.map(Duration::from_secs),
+			exit_timer: None,
+			idle: false,
+
+			once: setup.once,
+			played: false,
+
+			health: Rc::new(RefCell::new(Health::new())),
+
+			shutdown: false,
+			shutdown_grace: Duration::from_millis(setup.shutdown_grace),
+			shutdown_deadline: None,
+			shutdown_timer: None,
+			authenticate: setup.authenticate,
+			null_sink: null_sink,
+			signal: Box::new(tokio_signal::ctrl_c().flatten_stream()),
+			#[cfg(unix)]
+			debug_logging_signal: debug_logging_signal(),
+
+			event_channel: None,
+			lms: lms,
+			lms_failures: lms_failures,
+			event_script: setup.event_script,
+			print_events: setup.print_events,
+			discard_credentials: setup.discard_credentials
+		};
+
+		if let Some(port) = setup.health_port {
+			let ident = device_id(&format!("{} remote", task.session_config.device_id));
+
+			if let Err(e) = health::serve(SocketAddr::new(setup.health_address, port), task.health.clone(), ident, &handle) {
+				writeln!(stderr(), "error: failed to start the health endpoint on {}:{}: {}", setup.health_address, port, e).unwrap();
+			}
+		}
+
+		// nobody has picked us yet - that's idle, too
+		task.start_idle_timer();
+
+		if setup.enable_discovery {
+			task.restart_discovery();
+		}
+
+		if let Some(credentials) = setup.credentials {
+			task.credentials(credentials);
+		}
+
+		task.exit_if_unreachable();
+
+		task
+	}
+
+	// without discovery and without credentials nobody could ever get us connected - don't just sit there
+	fn exit_if_unreachable(&self) {
+		let discovering = self.discovery.is_some() || self.discovery_retry.is_some();
+
+		if !discovering && self.last_credentials.is_none() {
+			exit_with_error("no_connection_possible", "Discovery isn't available and there are no credentials - use --cache or --username/--password to provide credentials".to_string());
+		}
+	}
+
+	fn load_next_window(&mut self) {
+		if let (Some(context), Some(session)) = (self.context.take(), self.session.as_ref()) {
+			self.context_load = Some(load_window(session, &self.session_config.device_id, context, 0, CONTEXT_WINDOW, self.filter_explicit, 0));
+		}
+	}
+
+	fn restart_discovery(&mut self) {
+		let config = self.connect_config.clone();
+		let device_id = self.session_config.device_id.clone();
+
+		match discovery(&self.handle, config, device_id, 0) {
+			Ok(discovery) => {
+				self.discovery = Some(discovery);
+				self.discovery_attempts = 0;
+			}
+			Err(e) => {
+				self.discovery_attempts += 1;
+
+				// the port might be taken, or we're not allowed to listen - keep going with the credentials we have
+				if self.discovery_attempts >= DISCOVERY_MAX_ATTEMPTS {
+					writeln!(stderr(), "error: failed to start discovery: {} - giving up, continuing without discovery", e).unwrap();
+					self.exit_if_unreachable();
+				}
+				else {
+					writeln!(stderr(), "error: failed to start discovery: {} - retrying in {}s", e, DISCOVERY_RETRY_DELAY).unwrap();
+					self.discovery_retry = Some(Timeout::new(Duration::from_secs(DISCOVERY_RETRY_DELAY), &self.handle).unwrap());
+				}
+			}
+		}
+	}
+
+	// wait a bit longer after every failed attempt before we connect again
+	fn try_to_reconnect(&mut self) {
+		if self.shutdown || self.last_credentials.is_none() {
+			return;
+		}
+
+		let delay = 1u64 << self.reconnect_attempts.min(RECONNECT_MAX_BACKOFF);
+		self.reconnect_attempts += 1;
+
+		{
+			let mut health = self.health.borrow_mut();
+			health.connected = false;
+			health.reconnects += 1;
+		}
+
+		// the access point resolver only lets us choose the port - a different port gets us a different AP
+		if self.force_ap_retry && self.reconnect_attempts >= AP_RETRY_AFTER {
+			let ap_port = match self.session_config.ap_port {
+				Some(4070) => 443,
+				Some(443) => 80,
+				_ => 4070
+			};
+
+			writeln!(stderr(), "Trying access points on port {}", ap_port).unwrap();
+			self.session_config.ap_port = Some(ap_port);
+		}
+
+		// good enough a random number to keep a bunch of instances from reconnecting in lockstep
+		let jitter = if self.reconnect_jitter > 0 {
+			let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.subsec_nanos()).unwrap_or(0);
+			(nanos as u64 ^ process::id() as u64 * 2654435761) % (self.reconnect_jitter + 1)
+		} else {
+			0
+		};
+
+		writeln!(stderr(), "Reconnecting in {}.{:03}s (attempt {})", delay + jitter / 1000, jitter % 1000, self.reconnect_attempts).unwrap();
+		self.reconnect_timer = Some(Timeout::new(Duration::from_secs(delay) + Duration::from_millis(jitter), &self.handle).unwrap());
+	}
 
-			shutdown: false,
-			authenticate: setup.authenticate,
-			signal: Box::new(tokio_signal::ctrl_c().flatten_stream()),
+	// (re-)start counting the time without playback
+	fn start_idle_timer(&mut self) {
+		if let Some(timeout) = self.session_timeout {
+			self.idle_timer = Some(Timeout::new(timeout, &self.handle).unwrap());
+		}
 
-			event_channel: None,
-			lms: setup.lms
+		if let Some(timeout) = self.exit_on_idle {
+			self.exit_timer = Some(Timeout::new(timeout, &self.handle).unwrap());
+		}
+	}
+
+	// librespot doesn't tell us about buffer underruns - a dropped connection is the best hint we get
+	fn adapt_bitrate(&mut self) {
+		let stable = self.connected_since.take()
+			.map_or(false, |since| since.elapsed() >= Duration::from_secs(ADAPTIVE_BITRATE_STABLE));
+
+		let bitrate = match (self.player_config.bitrate, stable) {
+			(Bitrate::Bitrate96, true) => Bitrate::Bitrate160,
+			(Bitrate::Bitrate160, true) => Bitrate::Bitrate320,
+			(Bitrate::Bitrate320, false) => Bitrate::Bitrate160,
+			(Bitrate::Bitrate160, false) => Bitrate::Bitrate96,
+			(bitrate, _) => bitrate
 		};
 
-		if setup.enable_discovery {
-			let config = task.connect_config.clone();
-			let device_id = task.session_config.device_id.clone();
+		if bitrate != self.player_config.bitrate {
+			writeln!(stderr(), "Switching bitrate to {:?}", bitrate).unwrap();
+			self.player_config.bitrate = bitrate;
+		}
+	}
 
-			task.discovery = Some(discovery(&handle, config, device_id, 0).unwrap());
+	fn save_last_played(&self) {
+		if let (Some(ref path), Some(track)) = (self.resume_last.as_ref(), self.now_playing) {
+			let last_played = json!({
+				"context": self.playing_context,
+				"track": track.to_uri(),
+				"position": self.position.current()
+			});
+
+			if let Err(e) = File::create(path).and_then(|mut file| file.write_all(last_played.to_string().as_bytes())) {
+				writeln!(stderr(), "error: can't write {}: {}", path.display(), e).unwrap();
+			}
 		}
+	}
 
-		if let Some(credentials) = setup.credentials {
-			task.credentials(credentials);
+	fn drop_spirc_and_try_to_reconnect(&mut self) {
+		if self.adaptive_bitrate {
+			self.adapt_bitrate();
 		}
 
-		task
+		// the new spirc won't know what was going on - remember it if we're to pick it up again
+		if self.resume_on_reconnect && self.position.is_playing() {
+			if let Some(track) = self.now_playing {
+				let position = self.position.current().saturating_sub(RESUME_REWIND);
+				writeln!(stderr(), "Going to resume {} at {}s once reconnected", track.to_uri(), position / 1000).unwrap();
+				self.resume = Some((track, position));
+			}
+		}
+
+		self.position.stop();
+		self.spirc = None;
+		self.spirc_task = None;
+		self.event_channel = None;
+
+		self.try_to_reconnect();
 	}
 
 	fn credentials(&mut self, credentials: Credentials) {
 		let config = self.session_config.clone();
 		let handle = self.handle.clone();
 
+		self.idle = false;
+		self.idle_timer = None;
+
+		self.last_credentials = Some(credentials.clone());
+
 		let connection = Session::connect(config, credentials, self.cache.clone(), handle);
 
 		self.connect = connection;
@@ -352,16 +2003,75 @@ impl Future for Main {
 		loop {
 			let mut progress = false;
 
-			if let Some(Async::Ready(Some(creds))) = self.discovery.as_mut().map(|d| d.poll().unwrap()) {
-				if let Some(ref spirc) = self.spirc {
-					spirc.shutdown();
+			match self.discovery.as_mut().map(|d| d.poll()) {
+				Some(Ok(Async::Ready(Some(creds)))) => {
+					if let Some(ref spirc) = self.spirc {
+						spirc.shutdown();
+					}
+					self.credentials(creds);
+
+					progress = true;
+				}
+				Some(Err(e)) => {
+					// mDNS can be flaky on some platforms - drop the stream and build a new one in a moment
+					writeln!(stderr(), "error: discovery failed: {} - restarting in {}s", e, DISCOVERY_RETRY_DELAY).unwrap();
+					self.discovery = None;
+					self.discovery_retry = Some(Timeout::new(Duration::from_secs(DISCOVERY_RETRY_DELAY), &self.handle).unwrap());
+
+					progress = true;
+				}
+				_ => ()
+			}
+
+			if let Some(Ok(Async::Ready(()))) = self.discovery_retry.as_mut().map(|t| t.poll()) {
+				self.discovery_retry = None;
+				self.restart_discovery();
+
+				progress = true;
+			}
+
+			let connection = match self.connect.poll() {
+				Ok(connection) => connection,
+				Err(e) => {
+					writeln!(stderr(), "error: failed to connect to Spotify: {}", connect_error(&e)).unwrap();
+					self.connect = Box::new(futures::future::empty());
+
+					if is_auth_error(&e) && !self.retry_auth_errors {
+						// whoever picks us next brings credentials of their own - without discovery nobody will
+						if self.discovery.is_none() && self.discovery_retry.is_none() {
+							exit_with_error("invalid_credentials", connect_error(&e));
+						}
+
+						self.last_credentials = None;
+					}
+
+					self.try_to_reconnect();
+
+					progress = true;
+					Async::NotReady
+				}
+			};
+
+			if let Some(Ok(Async::Ready(()))) = self.reconnect_timer.as_mut().map(|t| t.poll()) {
+				self.reconnect_timer = None;
+
+				if let Some(credentials) = self.last_credentials.clone() {
+					self.credentials(credentials);
 				}
-				self.credentials(creds);
 
 				progress = true;
 			}
 
-			if let Async::Ready(ref mut session) = self.connect.poll().unwrap() {
+			if let Async::Ready(ref session) = connection {
+				// we're back after a drop - LMS's idea of the player state might be stale
+				if self.reconnect_attempts > 0 && !self.authenticate {
+					self.lms.notify(&json!(["spottyconnect", "reconnected"]), &self.handle);
+				}
+
+				self.reconnect_attempts = 0;
+				self.health.borrow_mut().connected = true;
+				forget_credentials(&self.discard_credentials);
+
 				if self.authenticate {
 					if !self.shutdown {
 						if let Some(ref spirc) = self.spirc {
@@ -374,7 +2084,14 @@ impl Future for Main {
 					}
 				}
 				else {
+					// let LMS know what kind of device we are, so it can show the right icon
+					self.lms.notify(&json!(["spottyconnect", "device", self.connect_config.device_type.to_string().to_lowercase()]), &self.handle);
+
 					self.connect = Box::new(futures::future::empty());
+					self.connected_since = Some(Instant::now());
+					self.session = Some(session.clone());
+					self.health.borrow_mut().session = Some(session.clone());
+					self.start_idle_timer();
 					let player_config = self.player_config.clone();
 					let connect_config = self.connect_config.clone();
 
@@ -387,46 +2104,279 @@ impl Future for Main {
 					let mixer = (mixer::find(Some("softvol")).unwrap())(Some(mixer_config));
 
 					let audio_filter = mixer.get_audio_filter();
-					let backend = audio_backend::find(None).unwrap();
+					let backend = audio_backend::find(None)
+						.unwrap_or_else(|| exit_with_error("audio_output_failed", "no audio backend available".to_string()));
+					let null_sink = self.null_sink;
 					let (player, event_channel) = Player::new(player_config, session.clone(), audio_filter, move || {
-						(backend)(Some(NULLDEVICE.to_string()))
+						if null_sink {
+							return Box::new(NullSink) as Box<audio_backend::Sink>;
+						}
+
+						// LMS streams the audio itself - null_device_writable() made sure we can get rid of ours
+						backend(Some(NULLDEVICE.to_string()))
 					});
 
 					let (spirc, spirc_task) = Spirc::new(connect_config, session.clone(), player, mixer);
 					self.spirc = Some(spirc);
 					self.spirc_task = Some(spirc_task);
 					self.event_channel = Some(event_channel);
+
+					if let Some((track, position)) = self.resume.take() {
+						self.resume_load = Some(resume_track(session, &self.session_config.device_id, track, position));
+					}
+
+					if let Some(context_uri) = self.play_context.take() {
+						self.playing_context = Some(context_uri.clone());
+						self.context_load = Some(load_context(session, &self.session_config.device_id, context_uri, self.start_index, CONTEXT_WINDOW, self.filter_explicit));
+					}
+					else if let Some((context_uri, track, position)) = self.last_played.take() {
+						writeln!(stderr(), "Resuming {} at {}s", track.to_uri(), position / 1000).unwrap();
+
+						match context_uri {
+							Some(context_uri) => {
+								self.playing_context = Some(context_uri.clone());
+								self.resuming_last = true;
+								self.context_load = Some(resume_context(session, &self.session_config.device_id, context_uri, track, position, CONTEXT_WINDOW, self.filter_explicit));
+							}
+							None => self.resume_load = Some(resume_track(session, &self.session_config.device_id, track, position))
+						}
+					}
 				}
 
 				progress = true;
 			}
 
-			if let Async::Ready(Some(())) = self.signal.poll().unwrap() {
-				if !self.shutdown {
-					if let Some(ref spirc) = self.spirc {
-						spirc.shutdown();
+			match self.signal.poll() {
+				Ok(Async::Ready(Some(()))) => {
+					if !self.shutdown {
+						self.save_last_played();
+
+						if let Some(ref spirc) = self.spirc {
+							spirc.shutdown();
+						}
+						self.shutdown = true;
+					} else {
+						return Ok(Async::Ready(()));
+					}
+
+					progress = true;
+				}
+				Err(e) => {
+					// don't let a signal handling glitch kill playback - just listen again
+					writeln!(stderr(), "error: failed to handle signal: {}", e).unwrap();
+					self.signal = Box::new(tokio_signal::ctrl_c().flatten_stream());
+				}
+				_ => ()
+			}
+
+			match self.resume_load.as_mut().map(|load| load.poll()) {
+				Some(Ok(Async::Ready(()))) => {
+					self.resume_load = None;
+					progress = true;
+				}
+				Some(Err(e)) => {
+					writeln!(stderr(), "error: failed to resume playback: {:?}", e).unwrap();
+					self.resume_load = None;
+					progress = true;
+				}
+				_ => ()
+			}
+
+			match self.context_load.as_mut().map(|load| load.poll()) {
+				Some(Ok(Async::Ready((context, window)))) => {
+					self.context_load = None;
+					self.context_window = match (window.first(), window.last()) {
+						(Some(first), Some(last)) => Some((*first, *last)),
+						_ => None
+					};
+					self.context = if context.is_empty() { None } else { Some(context) };
+					progress = true;
+				}
+				Some(Err(e)) => {
+					// we can't play what we were asked to - but once it's playing, keep going with what we've got
+					if self.context_window.is_none() && !self.resuming_last {
+						exit_with_error(e, format!("failed to play the context given by --play-context, starting at index {}", self.start_index));
 					}
+
+					writeln!(stderr(), "error: failed to load more tracks of the context: {}", e).unwrap();
+					self.context_load = None;
+					progress = true;
+				}
+				_ => ()
+			}
+
+			if let Some(Ok(Async::Ready(()))) = self.idle_timer.as_mut().map(|t| t.poll()) {
+				self.idle_timer = None;
+
+				if let Some(ref spirc) = self.spirc {
+					writeln!(stderr(), "Releasing the Spotify session after {} minutes without playback", self.session_timeout.unwrap().as_secs() / 60).unwrap();
+					spirc.shutdown();
+					self.idle = true;
+					self.lms.notify(&json!(["spottyconnect", "idle"]), &self.handle);
+				}
+
+				progress = true;
+			}
+
+			if let Some(Ok(Async::Ready(()))) = self.exit_timer.as_mut().map(|t| t.poll()) {
+				self.exit_timer = None;
+
+				if !self.shutdown {
+					writeln!(stderr(), "Quitting after {}s without playback", self.exit_on_idle.unwrap().as_secs()).unwrap();
+					self.lms.notify(&json!(["spottyconnect", "exit"]), &self.handle);
 					self.shutdown = true;
-				} else {
-					return Ok(Async::Ready(()));
+
+					// without a spirc to wait for, give the notification its grace period right away
+					match self.spirc {
+						Some(ref spirc) => spirc.shutdown(),
+						None => self.shutdown_deadline = Some(Instant::now() + self.shutdown_grace)
+					}
+				}
+
+				progress = true;
+			}
+
+			#[cfg(unix)]
+			{
+				match self.debug_logging_signal.poll() {
+					Ok(Async::Ready(Some(_))) => {
+						toggle_debug_logging();
+						progress = true;
+					}
+					Err(e) => {
+						writeln!(stderr(), "error: failed to handle signal: {}", e).unwrap();
+						self.debug_logging_signal = debug_logging_signal();
+					}
+					_ => ()
+				}
+			}
+
+			// rather stop than play something LMS doesn't know about
+			if let Some(Ok(Async::Ready(Some(())))) = self.lms_failures.as_mut().map(|failures| failures.poll()) {
+				if let Some(ref spirc) = self.spirc {
+					writeln!(stderr(), "error: failed to notify LMS - pausing playback").unwrap();
+					spirc.pause();
 				}
 
 				progress = true;
 			}
 
+			let mut spirc_finished = false;
+			let mut spirc_failed = false;
+			let mut spirc_released = false;
 			if let Some(ref mut spirc_task) = self.spirc_task {
-				if let Async::Ready(()) = spirc_task.poll().unwrap() {
-					if self.shutdown {
-						return Ok(Async::Ready(()));
-					} else {
-						panic!("Spirc shut down unexpectedly");
+				match spirc_task.poll() {
+					Ok(Async::Ready(())) => {
+						if self.shutdown {
+							spirc_finished = true;
+						} else if self.idle {
+							spirc_released = true;
+						} else {
+							writeln!(stderr(), "error: Spirc shut down unexpectedly").unwrap();
+							spirc_failed = true;
+						}
+					}
+					Err(_) => {
+						writeln!(stderr(), "error: Spirc failed").unwrap();
+						spirc_failed = true;
 					}
+					Ok(Async::NotReady) => ()
+				}
+			}
+
+			if spirc_failed {
+				self.drop_spirc_and_try_to_reconnect();
+				progress = true;
+			}
+
+			if spirc_released {
+				// discovery is still listening - the next user picking us will bring us back
+				self.spirc = None;
+				self.spirc_task = None;
+				self.event_channel = None;
+				self.connected_since = None;
+				self.health.borrow_mut().connected = false;
+				self.health.borrow_mut().session = None;
+
+				if let Some(session) = self.session.take() {
+					session.shutdown();
 				}
+
+				progress = true;
+			}
+
+			if spirc_finished {
+				// give the final notifications to LMS a chance to get out before we quit
+				self.spirc_task = None;
+				self.shutdown_deadline = Some(Instant::now() + self.shutdown_grace);
+				progress = true;
 			}
 
 			if let Some(ref mut event_channel) = self.event_channel {
 				if let Async::Ready(Some(event)) = event_channel.poll().unwrap() {
+					if self.print_events {
+						print_event(&event);
+					}
+
+					if let Some(ref event_script) = self.event_script {
+						event_script.run(&event);
+					}
+
+					self.health.borrow_mut().last_event = Some(SystemTime::now());
+					self.position.update(&event);
+
+					match event {
+						PlayerEvent::Started { track_id } => {
+							self.idle_timer = None;
+							self.exit_timer = None;
+							self.played = true;
+							self.now_playing = Some(track_id);
+						}
+						PlayerEvent::Stopped { .. } => {
+							self.start_idle_timer();
+							self.save_last_played();
+						}
+						PlayerEvent::Changed { old_track_id, new_track_id } => {
+							self.now_playing = Some(new_track_id);
+
+							// the player went from the window's last track back to its first - time for the next one
+							if self.context_window == Some((new_track_id, old_track_id)) {
+								self.load_next_window();
+							}
+
+							self.save_last_played();
+						}
+						_ => ()
+					}
+
+					// the player reports a pause as Stopped, too - only moving on to another track tells us the first one is done
+					let track_done = match event {
+						PlayerEvent::Changed { old_track_id, new_track_id } => self.played && old_track_id != new_track_id,
+						_ => false
+					};
+
+					if self.once && track_done && !self.shutdown {
+						if let Some(ref spirc) = self.spirc {
+							spirc.shutdown();
+						}
+						self.shutdown = true;
+					}
+
 					self.lms.signal_event(event, self.handle.clone());
+					progress = true;
+				}
+			}
+
+			if let Some(deadline) = self.shutdown_deadline {
+				if !progress {
+					if self.lms.pending() == 0 || Instant::now() >= deadline {
+						return Ok(Async::Ready(()));
+					}
+
+					// check back shortly
+					let mut timer = Timeout::new(Duration::from_millis(50), &self.handle).unwrap();
+					let _ = timer.poll();
+					self.shutdown_timer = Some(timer);
 				}
 			}
 
@@ -449,70 +2399,353 @@ fn main() {
 		connect_config,
 		credentials,
 		authenticate,
+		refresh_credentials,
 		enable_discovery,
 		get_token,
 		save_token,
 		client_id,
 		scope,
 		single_track,
+		metadata_header,
+		passthrough_chunk_size,
+		metadata_cache,
+		prefetch,
+		probe_metadata,
 		start_position,
-		lms
+		repeat,
+		initial_seek_retry,
+		mut lms,
+		test_lms,
+		fake_track,
+		event_script,
+		print_events,
+		shutdown_grace,
+		discard_credentials,
+		list_devices,
+		get_state,
+		transfer_to,
+		play_context,
+		start_index,
+		filter_explicit,
+		force_ap_retry,
+		connect_retry_on_auth_error,
+		adaptive_bitrate,
+		session_timeout,
+		exit_on_idle,
+		once,
+		health_port,
+		health_address,
+		reconnect_jitter,
+		resume_on_reconnect,
+		resume_last,
+		startup_json,
+		pause_on_lms_failure
 	} = setup(&args.clone());
 
-	if let Some(ref track_id) = single_track {
-		match credentials {
-			Some(credentials) => {
-				let backend = audio_backend::find(None).unwrap();
+	if let Some(capabilities) = startup_json {
+		// same order as below
+		let mode = if fake_track.is_some() { "fake-track" }
+			else if test_lms { "test-lms" }
+			else if single_track.is_some() { "single-track" }
+			else if prefetch.is_some() { "prefetch" }
+			else if probe_metadata.is_some() { "probe-metadata-batch" }
+			else if transfer_to.is_some() { "transfer-to" }
+			else if get_state { "get-state" }
+			else if list_devices { "list-devices" }
+			else if authenticate && !enable_discovery { "authenticate" }
+			else if refresh_credentials { "refresh-credentials" }
+			else if get_token { "get-token" }
+			else if play_context.is_some() { "play-context" }
+			else { "connect" };
+
+		let banner = json!({
+			"version": VERSION.to_string(),
+			"pid": process::id(),
+			"mode": mode,
+			"capabilities": capabilities
+		});
 
-				let track = SpotifyId::from_uri(
-									track_id.replace("spotty://", "spotify:")
-									.replace("://", ":")
-									.as_str());
+		// stdout is reserved for the audio data when we're streaming
+		if fake_track.is_some() || single_track.is_some() {
+			writeln!(stderr(), "{}", banner.to_string()).unwrap();
+		}
+		else {
+			println!("{}", banner.to_string());
+		}
+	}
 
-				let session = core.run(Session::connect(session_config.clone(), credentials, cache.clone(), handle)).unwrap();
+	if let Some(path) = fake_track {
+		let mut file = File::open(&path).unwrap_or_else(|e| single_track_error("invalid_track", format!("can't open {}: {}", path, e)));
 
-				let (player, _) = Player::new(player_config, session.clone(), None, move || (backend)(None));
+		// there's no Spotify track behind it - derive a stable ID from the path
+		let mut hasher = Sha1::new();
+		hasher.input_str(&path);
+		let track_id = SpotifyId::from_base16(&hasher.result_str()[..32]).unwrap();
+		let previous_id = SpotifyId::from_base16(&"0".repeat(32)).unwrap();
 
-				core.run(player.load(track.unwrap(), true, start_position)).unwrap();
+		let mut play = |event: PlayerEvent| {
+			if print_events {
+				print_event(&event);
 			}
-			None => {
-				println!("Missing credentials");
+
+			if let Some(ref event_script) = event_script {
+				event_script.run(&event);
 			}
+			lms.signal_event(event, handle.clone());
+		};
+
+		play(PlayerEvent::Changed { old_track_id: previous_id, new_track_id: track_id });
+		play(PlayerEvent::Started { track_id: track_id });
+
+		if let Err(e) = io::copy(&mut file, &mut io::stdout()) {
+			single_track_error("write_failed", e.to_string());
+		}
+
+		play(PlayerEvent::Stopped { track_id: track_id });
+
+		// the notifications are still on their way
+		let deadline = Instant::now() + Duration::from_millis(shutdown_grace);
+		while lms.pending() > 0 && Instant::now() < deadline {
+			core.turn(Some(Duration::from_millis(50)));
 		}
 	}
-	else if authenticate && !enable_discovery {
-		core.run(Session::connect(session_config.clone(), credentials.unwrap(), cache.clone(), handle)).unwrap();
-		println!("authorized");
+	else if test_lms {
+		let result = core.run(lms.test_connection(handle)).unwrap();
+		println!("{}", result.to_string());
+
+		if result.get("error").is_some() {
+			exit(1);
+		}
 	}
-	else if get_token {
-		if let Some(client_id) = client_id {
-			let session = core.run(Session::connect(session_config, credentials.unwrap(), cache.clone(), handle)).unwrap();
-			let scope = scope.unwrap_or("user-read-private,playlist-read-private,playlist-read-collaborative,playlist-modify-public,playlist-modify-private,user-follow-modify,user-follow-read,user-library-read,user-library-modify,user-top-read,user-read-recently-played".to_string());
-			let url = format!("hm://keymaster/token/authenticated?client_id={}&scope={}", client_id, scope);
+	else if let Some(ref track_id) = single_track {
+		let credentials = credentials_or_exit(credentials, single_track_error);
+
+		let backend = audio_backend::find(None)
+			.unwrap_or_else(|| single_track_error("audio_output_failed", "no audio backend available".to_string()));
+
+		if let Err(e) = output_writable(None) {
+			single_track_error("audio_output_failed", format!("can't open the audio output: {}", e));
+		}
+
+		let track = parse_track_id(track_id).unwrap_or_else(|| {
+			single_track_error("invalid_track_id", format!("'{}' is not a valid track ID", track_id))
+		});
+
+		let session = connect_or_exit(&mut core, &session_config, Some(credentials), &cache, &discard_credentials, single_track_error);
+
+		// better not play anything than something we're not sure about
+		if filter_explicit {
+			let explicit_session = session.clone();
+			let explicit = core.run(mercury_with_retry(track.to_uri(), &handle, move || metadata::is_explicit(&explicit_session, track)))
+				.unwrap_or_else(|_| single_track_error("metadata_failed", format!("can't tell whether track {} is explicit", track_id)));
+
+			if explicit {
+				single_track_error("explicit_track", format!("track {} is marked as explicit by Spotify", track_id));
+			}
+		}
+
+		// a single line of JSON ahead of the OGG data tells the consumer what it's getting
+		if metadata_header {
+			let metadata_session = session.clone();
+			let metadata = mercury_with_retry(track.to_uri(), &handle, move || match metadata_cache {
+				Some(ref metadata_cache) => metadata_cache.track_json(&metadata_session, track),
+				None => metadata::track_json(&metadata_session, track)
+			});
+
+			let header = core.run(metadata)
+				.unwrap_or_else(|_| json!({ "id": track.to_uri() }));
+
+			let mut stdout = io::stdout();
+			writeln!(stdout, "{}", header.to_string())
+				.and_then(|_| stdout.flush())
+				.unwrap_or_else(|e| single_track_error("write_failed", e.to_string()));
+		}
+
+		let written = Arc::new(AtomicBool::new(false));
+		let sink_written = written.clone();
+
+		let (player, _) = Player::new(player_config, session.clone(), None, move || {
+			let sink = backend(None);
+
+			let sink = match passthrough_chunk_size {
+				Some(size) => Box::new(ChunkedSink { sink: sink, chunk_size: size / 2, buffer: Vec::new() }) as Box<audio_backend::Sink>,
+				None => sink
+			};
+
+			Box::new(WrittenSink { sink: sink, written: sink_written }) as Box<audio_backend::Sink>
+		});
+
+		let mut attempt = 0;
+		let mut start_position = start_position;
 
-			let result = core.run(Box::new(session.mercury().get(url).map(move |response| {
-				let data = response.payload.first().expect("Empty payload");
-				let token = String::from_utf8(data.clone()).unwrap();
+		// seeking into a track over a slow link can fail - but load() only returns once the track is over, so only
+		// try again if nothing has been written yet
+		loop {
+			match core.run(player.load(track, true, start_position)) {
+				Ok(_) if repeat => {
+					start_position = 0;
+				}
+				Err(_) if start_position > 0 && attempt < initial_seek_retry && !written.load(Ordering::SeqCst) => {
+					attempt += 1;
+					info!("Failed to load track at {}ms, retrying ({}/{})", start_position, attempt, initial_seek_retry);
+				}
+				Err(_) => {
+					// the player doesn't tell us why: the track might be unavailable, or we failed to fetch or decode it
+					single_track_error("load_failed", format!("failed to load track {}", track_id));
+				}
+				Ok(_) => break
+			}
+		}
+	}
+	else if let Some((cache_location, track_ids)) = prefetch {
+		let session = connect_or_exit(&mut core, &session_config, credentials, &cache, &discard_credentials, exit_with_error);
+
+		// "play" the tracks as fast as they come in - librespot stores each file in the cache once it's complete
+		let (player, _) = Player::new(player_config, session.clone(), None, || Box::new(NullSink) as Box<audio_backend::Sink>);
 
-				if let Some(save_token) = save_token {
-					let mut file = File::create(save_token.to_string()).expect("Can't create token file");
-					file.write(&token.clone().into_bytes()).expect("Can't write token file");
+		let results: Vec<_> = track_ids.iter().map(|track_id| {
+			let track = match parse_track_id(track_id) {
+				Some(track) => track,
+				None => return json!({ "track": track_id, "error": "invalid_track_id" })
+			};
+
+			if core.run(player.load(track, true, 0)).is_err() {
+				return json!({ "track": track_id, "error": "load_failed" });
+			}
+
+			let files_session = session.clone();
+			let files = match core.run(mercury_with_retry(track.to_uri(), &handle, move || metadata::audio_files(&files_session, track))) {
+				Ok(files) => files,
+				Err(_) => return json!({ "track": track_id, "error": "metadata_failed" })
+			};
+
+			// the player is done with the track, but the cache might still be writing the file
+			let cache_location = cache_location.clone();
+			let cached = with_retries(CACHE_WRITE_ATTEMPTS, Duration::from_millis(CACHE_WRITE_DELAY), &handle, move |_| {
+				let found = files.iter().any(|file| cached_file(&cache_location, file).is_file());
+				Box::new(future::result(if found { Ok(()) } else { Err(()) }))
+			});
+
+			match core.run(cached) {
+				Ok(_) => json!({ "track": track_id, "cached": true }),
+				Err(_) => json!({ "track": track_id, "error": "not_cached" })
+			}
+		}).collect();
+
+		println!("{}", json!(results).to_string());
+	}
+	else if let Some(track_ids) = probe_metadata {
+		let session = connect_or_exit(&mut core, &session_config, credentials, &cache, &discard_credentials, exit_with_error);
+
+		// one at a time - a whole playlist at once would be asking for trouble with Spotify
+		let results: Vec<_> = track_ids.iter().map(|track_id| {
+			let track = match parse_track_id(track_id) {
+				Some(track) => track,
+				None => return json!({ "track": track_id, "error": "invalid_track_id" })
+			};
+
+			// a lookup which hangs must not hold up the whole batch
+			let metadata_session = session.clone();
+			let metadata_cache = metadata_cache.clone();
+			let metadata = mercury_with_retry(track.to_uri(), &handle, move || match metadata_cache {
+				Some(ref metadata_cache) => metadata_cache.track_json(&metadata_session, track),
+				None => metadata::track_json(&metadata_session, track)
+			});
+
+			match core.run(metadata) {
+				Ok(mut metadata) => {
+					if let Some(metadata) = metadata.as_object_mut() {
+						metadata.insert("track".to_string(), json!(track_id));
+					}
+					metadata
 				}
-				else {
-					println!("{}", token);
+				Err(_) => json!({ "track": track_id, "error": "metadata_failed" })
+			}
+		}).collect();
+
+		println!("{}", json!(results).to_string());
+	}
+	else if list_devices || get_state || transfer_to.is_some() {
+		let session = connect_or_exit(&mut core, &session_config, credentials, &cache, &discard_credentials, exit_with_error);
+
+		// use an ident of our own, or a spotty Connect instance with the same name would ignore us
+		let ident = device_id(&format!("{} remote", session_config.device_id));
+
+		if let Some(target) = transfer_to {
+			match core.run(remote::transfer(&session, ident, target.clone(), &handle)) {
+				Ok(_) => println!("{}", json!({ "transferred": target }).to_string()),
+				Err(e) => exit_with_error(e, format!("failed to transfer playback to {}", target))
+			}
+		}
+		else {
+			let devices_session = session.clone();
+			let devices_handle = handle.clone();
+			let devices = mercury_with_retry("the Spotify Connect devices".to_string(), &handle, move || remote::devices(&devices_session, ident.clone(), &devices_handle));
+
+			match core.run(devices) {
+				Ok(ref devices) if get_state => println!("{}", remote::state_json(devices).to_string()),
+				Ok(devices) => {
+					let devices: Vec<_> = devices.iter().map(remote::device_json).collect();
+					println!("{}", json!(devices).to_string());
 				}
-			})));
+				Err(e) => exit_with_error("request_failed", format!("{:?}", e))
+			}
+		}
+	}
+	else if authenticate && !enable_discovery {
+		let credentials = credentials.unwrap_or_else(|| exit_with_error("missing_credentials", "Use --username/--password to provide credentials".to_string()));
+
+		// that's where the credentials get stored - nothing to forget
+		connect_or_exit(&mut core, &session_config, Some(credentials), &cache, &None, exit_with_error);
+		println!("authorized");
+	}
+	else if refresh_credentials {
+		let credentials = credentials.unwrap_or_else(|| exit_with_error("missing_credentials", "There are no cached credentials to refresh - use --authenticate first".to_string()));
+
+		// librespot stores the reusable credentials Spotify hands out on every sign in
+		let session = connect_or_exit(&mut core, &session_config, Some(credentials), &cache, &None, exit_with_error);
 
-			match result {
-				Ok(_) => (),
-				Err(e) => println!("error getting token {:?}", e),
+		println!("{}", json!({ "refreshed": session.username() }).to_string());
+	}
+	else if get_token {
+		if let Some(client_id) = client_id {
+			let session = connect_or_exit(&mut core, &session_config, credentials, &cache, &discard_credentials, exit_with_error);
+			let scope = scope.unwrap_or("user-read-private,playlist-read-private,playlist-read-collaborative,playlist-modify-public,playlist-modify-private,user-follow-modify,user-follow-read,user-library-read,user-library-modify,user-top-read,user-read-recently-played".to_string());
+			let url = format!("hm://keymaster/token/authenticated?client_id={}&scope={}", url_encode(&client_id), url_encode(&scope));
+
+			let token_session = session.clone();
+			let response = core.run(mercury_with_retry(url.clone(), &handle, move || token_session.mercury().get(url.clone())))
+				.unwrap_or_else(|e| exit_with_error("token_request_failed", format!("error getting token {:?}", e)));
+
+			// seen during Spotify outages - not something we could fix
+			let data = match response.payload.first() {
+				Some(data) if !data.is_empty() => data,
+				_ => exit_with_error("empty_token_payload", "Spotify returned an empty token response - this usually is a temporary issue on Spotify's side, please try again later".to_string())
+			};
+
+			let token = String::from_utf8(data.clone())
+				.map(|token| verify_scopes(token.trim(), &scope))
+				.unwrap_or_else(|e| exit_with_error("token_decode_failed", format!("the token response isn't valid UTF-8: {}", e)));
+
+			if let Some(save_token) = save_token {
+				File::create(&save_token)
+					.and_then(|mut file| file.write_all(token.as_bytes()))
+					.unwrap_or_else(|e| exit_with_error("save_token_failed", format!("can't write token file {}: {}", save_token, e)));
+			}
+			else {
+				println!("{}", token);
 			}
 		}
 		else {
-			println!("Use --client-id to provide a CLIENT_ID");
+			exit_with_error("missing_client_id", "Use --client-id to provide a CLIENT_ID".to_string());
 		}
 	}
 	else {
+		// without discovery the credentials are the only way to get connected
+		if !enable_discovery && credentials.is_none() {
+			exit_with_error("missing_credentials", "Discovery is disabled - use --cache or --username/--password to provide credentials".to_string());
+		}
+
 		core.run(Main::new(handle, Setup {
 			cache,
 			session_config,
@@ -520,15 +2753,124 @@ fn main() {
 			connect_config,
 			credentials,
 			authenticate,
+			refresh_credentials,
 			enable_discovery,
 			get_token,
 			save_token,
 			client_id,
 			scope,
 			single_track,
+			metadata_header,
+			passthrough_chunk_size,
+			metadata_cache,
+			prefetch,
+			probe_metadata,
 			start_position,
-			lms
+			repeat,
+			initial_seek_retry,
+			lms,
+			test_lms,
+			fake_track,
+			event_script,
+			print_events,
+			shutdown_grace,
+			discard_credentials,
+			list_devices,
+			get_state,
+			transfer_to,
+			play_context,
+			start_index,
+			filter_explicit,
+			force_ap_retry,
+			connect_retry_on_auth_error,
+			adaptive_bitrate,
+			session_timeout,
+			exit_on_idle,
+			once,
+			health_port,
+			health_address,
+			reconnect_jitter,
+			resume_on_reconnect,
+			resume_last,
+			startup_json: None,
+			pause_on_lms_failure
 		})).unwrap()
 	}
 }
 
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::Cell;
+
+	// a request failing the given number of times before it succeeds, counting its attempts
+	fn flaky(failures: u32, attempts: Rc<Cell<u32>>) -> impl FnMut(u32) -> Box<Future<Item=u32, Error=()>> {
+		move |attempt| {
+			attempts.set(attempts.get() + 1);
+			assert_eq!(attempt, attempts.get());
+			Box::new(future::result(if attempt > failures { Ok(attempt) } else { Err(()) }))
+		}
+	}
+
+	#[test]
+	fn url_encode_keeps_unreserved_and_commas() {
+		assert_eq!(url_encode("a b,c"), "a%20b,c");
+		assert_eq!(url_encode("user-read-private,streaming"), "user-read-private,streaming");
+		assert_eq!(url_encode("a&b=c/d?"), "a%26b%3Dc%2Fd%3F");
+		assert_eq!(url_encode("é"), "%C3%A9");
+	}
+
+	#[test]
+	fn track_ids_from_lms_urls() {
+		let track = SpotifyId::from_uri("spotify:track:4uLU6hMCjMI75M1A2tKUQC").ok();
+
+		assert!(track.is_some());
+		assert_eq!(parse_track_id("spotify:track:4uLU6hMCjMI75M1A2tKUQC"), track);
+		assert_eq!(parse_track_id("spotty://track:4uLU6hMCjMI75M1A2tKUQC"), track);
+		assert_eq!(parse_track_id("spotify://track:4uLU6hMCjMI75M1A2tKUQC"), track);
+		assert_eq!(parse_track_id("4uLU6hMCjMI75M1A2tKUQC"), None);
+	}
+
+	#[test]
+	fn features_are_implemented() {
+		for &(capability, implemented) in FEATURES {
+			assert!(implemented(), "{} is advertised but not implemented", capability);
+		}
+	}
+
+	#[test]
+	fn retries_until_success() {
+		let mut core = Core::new().unwrap();
+		let attempts = Rc::new(Cell::new(0));
+		let started = Instant::now();
+
+		let result = core.run(with_retries(3, Duration::from_millis(20), &core.handle(), flaky(2, attempts.clone())));
+
+		assert_eq!(result, Ok(3));
+		assert_eq!(attempts.get(), 3);
+		assert!(started.elapsed() >= Duration::from_millis(40));
+	}
+
+	#[test]
+	fn gives_up_after_attempts() {
+		let mut core = Core::new().unwrap();
+		let attempts = Rc::new(Cell::new(0));
+
+		let result = core.run(with_retries(3, Duration::from_millis(1), &core.handle(), flaky(5, attempts.clone())));
+
+		assert_eq!(result, Err(()));
+		assert_eq!(attempts.get(), 3);
+	}
+
+	#[test]
+	fn tries_at_least_once() {
+		let mut core = Core::new().unwrap();
+		let attempts = Rc::new(Cell::new(0));
+
+		let result = core.run(with_retries(0, Duration::from_millis(1), &core.handle(), flaky(0, attempts.clone())));
+
+		assert_eq!(result, Ok(1));
+		assert_eq!(attempts.get(), 1);
+	}
+}
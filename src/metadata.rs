@@ -0,0 +1,185 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use futures::{future, Future};
+use protobuf;
+use serde_json::{self, Value};
+
+use librespot::core::mercury::MercuryError;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{FileId, SpotifyId};
+use librespot::metadata::{Album, Artist, Metadata, Track};
+use librespot::protocol;
+
+// Spotify's image CDN - the cover's file ID is all it takes
+const ARTWORK_URL: &'static str = "https://i.scdn.co/image/";
+
+// what a consumer of the audio stream would want to know about the track: title, artists, album, duration and cover art
+pub fn track_json(session: &Session, track_id: SpotifyId) -> Box<Future<Item=Value, Error=MercuryError>> {
+	let session = session.clone();
+
+	let metadata = Track::get(&session, track_id)
+		.and_then(move |track| {
+			let album = Album::get(&session, track.album);
+			let artists = future::join_all(track.artists.iter()
+				.map(|artist| Artist::get(&session, *artist))
+				.collect::<Vec<_>>());
+
+			album.join(artists).map(move |(album, artists)| {
+				let artists: Vec<String> = artists.into_iter().map(|artist| artist.name).collect();
+				// not every album has a cover - that's an empty list then
+				let artwork: Vec<String> = album.covers.iter().map(|cover| format!("{}{}", ARTWORK_URL, cover.to_base16())).collect();
+
+				json!({
+					"id": track.id.to_uri(),
+					"title": track.name,
+					"artist": artists.join(", "),
+					"album": album.name,
+					"duration": track.duration as f64 / 1000.0,
+					"artwork": artwork
+				})
+			})
+		});
+
+	Box::new(metadata)
+}
+
+// librespot's Track doesn't carry Spotify's explicit flag - read it from the raw metadata
+pub fn is_explicit(session: &Session, track_id: SpotifyId) -> Box<Future<Item=bool, Error=MercuryError>> {
+	let explicit = session.mercury().get(format!("hm://metadata/3/track/{}", track_id.to_base16()))
+		.and_then(|response| {
+			let data = response.payload.first().ok_or(MercuryError)?;
+			let track = protobuf::parse_from_bytes::<protocol::metadata::Track>(data).map_err(|_| MercuryError)?;
+			Ok(track.get_explicit())
+		});
+
+	Box::new(explicit)
+}
+
+// the audio files the player could pick for a track: any of its formats, or those of its alternatives if the track itself isn't available
+pub fn audio_files(session: &Session, track_id: SpotifyId) -> Box<Future<Item=Vec<FileId>, Error=MercuryError>> {
+	let session = session.clone();
+
+	let files = Track::get(&session, track_id)
+		.and_then(move |track| {
+			if track.available {
+				return future::Either::A(future::ok(track.files.values().cloned().collect()));
+			}
+
+			let alternatives = future::join_all(track.alternatives.iter()
+				.map(|alternative| Track::get(&session, *alternative))
+				.collect::<Vec<_>>());
+
+			future::Either::B(alternatives.map(|alternatives| alternatives.iter()
+				.flat_map(|alternative| alternative.files.values().cloned())
+				.collect()))
+		});
+
+	Box::new(files)
+}
+
+// number of tracks kept in memory
+const CACHE_SIZE: usize = 500;
+
+// the same tracks are looked up over and over again - keep their metadata in memory, and in the cache folder if we have one
+#[derive(Clone)]
+pub struct MetadataCache {
+	entries: Rc<RefCell<HashMap<SpotifyId, (Value, SystemTime)>>>,
+	// least recently used first
+	usage: Rc<RefCell<VecDeque<SpotifyId>>>,
+	dir: Option<PathBuf>,
+	ttl: Duration
+}
+
+impl MetadataCache {
+	pub fn new(dir: Option<PathBuf>, ttl: Duration) -> MetadataCache {
+		if let Some(ref dir) = dir {
+			if let Err(e) = fs::create_dir_all(dir) {
+				warn!("Can't create metadata cache folder {}: {}", dir.display(), e);
+			}
+		}
+
+		MetadataCache {
+			entries: Rc::new(RefCell::new(HashMap::new())),
+			usage: Rc::new(RefCell::new(VecDeque::new())),
+			dir: dir,
+			ttl: ttl
+		}
+	}
+
+	pub fn ttl(&self) -> Duration {
+		self.ttl
+	}
+
+	// same as track_json(), but only asks Spotify if we don't know the track yet
+	pub fn track_json(&self, session: &Session, track_id: SpotifyId) -> Box<Future<Item=Value, Error=MercuryError>> {
+		if let Some(metadata) = self.get(track_id) {
+			info!("Metadata of {} found in cache", track_id.to_uri());
+			return Box::new(future::ok(metadata));
+		}
+
+		let cache = self.clone();
+
+		Box::new(track_json(session, track_id).map(move |metadata| {
+			cache.put(track_id, metadata.clone());
+			metadata
+		}))
+	}
+
+	fn get(&self, track_id: SpotifyId) -> Option<Value> {
+		let cached = self.entries.borrow().get(&track_id).cloned();
+
+		let cached = cached.or_else(|| {
+			let path = self.path(track_id)?;
+			let stored = fs::metadata(&path).and_then(|file| file.modified()).ok()?;
+			let metadata = File::open(&path).ok().and_then(|file| serde_json::from_reader::<_, Value>(file).ok())?;
+			Some((metadata, stored))
+		});
+
+		match cached {
+			Some((metadata, stored)) if self.is_fresh(stored) => {
+				self.remember(track_id, metadata.clone(), stored);
+				Some(metadata)
+			}
+			_ => None
+		}
+	}
+
+	fn put(&self, track_id: SpotifyId, metadata: Value) {
+		if let Some(path) = self.path(track_id) {
+			if let Err(e) = File::create(&path).and_then(|mut file| file.write_all(metadata.to_string().as_bytes())) {
+				warn!("Can't write metadata cache file {}: {}", path.display(), e);
+			}
+		}
+
+		self.remember(track_id, metadata, SystemTime::now());
+	}
+
+	fn remember(&self, track_id: SpotifyId, metadata: Value, stored: SystemTime) {
+		let mut entries = self.entries.borrow_mut();
+		let mut usage = self.usage.borrow_mut();
+
+		usage.retain(|id| *id != track_id);
+		usage.push_back(track_id);
+		entries.insert(track_id, (metadata, stored));
+
+		while usage.len() > CACHE_SIZE {
+			if let Some(oldest) = usage.pop_front() {
+				entries.remove(&oldest);
+			}
+		}
+	}
+
+	fn is_fresh(&self, stored: SystemTime) -> bool {
+		stored.elapsed().map(|age| age < self.ttl).unwrap_or(true)
+	}
+
+	fn path(&self, track_id: SpotifyId) -> Option<PathBuf> {
+		self.dir.as_ref().map(|dir| dir.join(format!("{}.json", track_id.to_base16())))
+	}
+}
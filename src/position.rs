@@ -0,0 +1,92 @@
+use std::time::Instant;
+
+use librespot::playback::player::PlayerEvent;
+
+// the player doesn't tell us where it is - keep track of the position based on its events
+#[derive(Clone, Copy)]
+pub struct Position {
+	position: u32,
+	playing_since: Option<Instant>,
+}
+
+impl Position {
+	pub fn new() -> Position {
+		Position {
+			position: 0,
+			playing_since: None,
+		}
+	}
+
+	pub fn update(&mut self, event: &PlayerEvent) {
+		match *event {
+			PlayerEvent::Started { .. } => {
+				self.playing_since = Some(Instant::now());
+			}
+			PlayerEvent::Stopped { .. } => {
+				self.stop();
+			}
+			PlayerEvent::Seek { position } => {
+				self.position = position;
+				if self.playing_since.is_some() {
+					self.playing_since = Some(Instant::now());
+				}
+			}
+			PlayerEvent::Changed { .. } => {
+				self.position = 0;
+				self.playing_since = None;
+			}
+			PlayerEvent::Volume { .. } => {}
+		}
+	}
+
+	// keep the position reached so far, but don't count on the clock any more
+	pub fn stop(&mut self) {
+		self.position = self.current();
+		self.playing_since = None;
+	}
+
+	pub fn is_playing(&self) -> bool {
+		self.playing_since.is_some()
+	}
+
+	pub fn current(&self) -> u32 {
+		match self.playing_since {
+			Some(since) => {
+				let elapsed = since.elapsed();
+				self.position + (elapsed.as_secs() * 1000) as u32 + elapsed.subsec_nanos() / 1_000_000
+			}
+			None => self.position
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use librespot::core::spotify_id::SpotifyId;
+
+	fn track() -> SpotifyId {
+		SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap()
+	}
+
+	#[test]
+	fn follows_player_events() {
+		let mut position = Position::new();
+		assert_eq!(position.current(), 0);
+
+		position.update(&PlayerEvent::Seek { position: 5000 });
+		assert!(!position.is_playing());
+		assert_eq!(position.current(), 5000);
+
+		position.update(&PlayerEvent::Started { track_id: track() });
+		assert!(position.is_playing());
+		assert!(position.current() >= 5000);
+
+		position.update(&PlayerEvent::Stopped { track_id: track() });
+		assert!(!position.is_playing());
+		assert!(position.current() >= 5000);
+
+		position.update(&PlayerEvent::Changed { old_track_id: track(), new_track_id: track() });
+		assert_eq!(position.current(), 0);
+	}
+}
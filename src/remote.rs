@@ -0,0 +1,541 @@
+use std::collections::VecDeque;
+use std::cmp;
+use std::mem;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{future, Async, Future, Poll, Stream};
+use futures::sync::mpsc::UnboundedReceiver;
+use protobuf::{self, Message, RepeatedField};
+use serde_json::{self, Value};
+use tokio_core::reactor::{Handle, Timeout};
+
+use librespot::core::mercury::{MercuryError, MercuryResponse};
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::protocol::spirc::{CapabilityType, DeviceState, Frame, MessageType, PlayStatus, State, TrackRef};
+
+// seconds to wait for the Spotify Connect devices to answer
+const RESPONSE_TIMEOUT: u64 = 2;
+
+// the channel all Spotify Connect devices of a user are listening on
+fn remote_uri(session: &Session) -> String {
+	format!("hm://remote/user/{}/", session.username())
+}
+
+fn now_ms() -> i64 {
+	let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+	(since_epoch.as_secs() * 1000 + since_epoch.subsec_nanos() as u64 / 1_000_000) as i64
+}
+
+fn frame(ident: &str, typ: MessageType) -> Frame {
+	let mut frame = Frame::new();
+	frame.set_version(1);
+	frame.set_ident(ident.to_string());
+	frame.set_protocol_version("2.0.0".to_string());
+	frame.set_seq_nr(1);
+	frame.set_typ(typ);
+	frame.set_state_update_id(now_ms());
+	frame
+}
+
+// say hello like a Spotify application would, and collect the devices' notifications - they contain their state
+pub fn devices(session: &Session, ident: String, handle: &Handle) -> Box<Future<Item=Vec<Frame>, Error=MercuryError>> {
+	let uri = remote_uri(session);
+	let sender = session.clone();
+	let timeout = Timeout::new(Duration::from_secs(RESPONSE_TIMEOUT), handle).unwrap();
+
+	let devices = session.mercury().subscribe(uri.clone())
+		.and_then(move |responses| {
+			let mut hello = frame(&ident, MessageType::kMessageTypeHello);
+
+			// we're only listening - don't let anybody pick us as a player
+			let mut device_state = DeviceState::new();
+			device_state.set_can_play(false);
+			device_state.set_is_active(false);
+			hello.set_device_state(device_state);
+
+			sender.mercury().send(uri, hello.write_to_bytes().unwrap())
+				.map(move |_| Notifications {
+					responses: responses,
+					timeout: timeout,
+					ident: ident,
+					frames: Vec::new()
+				})
+		})
+		.and_then(|notifications| notifications);
+
+	Box::new(devices)
+}
+
+// hand the active device's playback state over to the target device, like the Spotify applications do
+pub fn transfer(session: &Session, ident: String, target: String, handle: &Handle) -> Box<Future<Item=(), Error=&'static str>> {
+	let uri = remote_uri(session);
+	let sender = session.clone();
+
+	let transfer = devices(session, ident.clone(), handle)
+		.map_err(|_| "request_failed")
+		.and_then(move |devices| {
+			if !devices.iter().any(|device| device.get_ident() == target) {
+				return Err("unknown_device");
+			}
+
+			match devices.into_iter().find(|device| device.get_device_state().get_is_active()) {
+				Some(active) => Ok((active, target)),
+				None => Err("no_active_playback")
+			}
+		})
+		.and_then(move |(active, target)| {
+			// nothing to do if it's playing there already
+			if active.get_ident() == target {
+				return future::Either::A(future::ok(()));
+			}
+
+			let mut state = active.get_state().clone();
+			let position = current_position(&state);
+			state.set_position_ms(position);
+			state.set_position_measured_at(now_ms() as u64);
+
+			let mut load = frame(&ident, MessageType::kMessageTypeLoad);
+			load.set_recipient(RepeatedField::from_vec(vec![target]));
+			load.set_state(state);
+
+			future::Either::B(sender.mercury().send(uri, load.write_to_bytes().unwrap())
+				.map(|_| ())
+				.map_err(|_| "request_failed"))
+		});
+
+	Box::new(transfer)
+}
+
+// albums, artists and playlists - the contexts a Spotify application could ask a device to play
+pub fn is_context_uri(uri: &str) -> bool {
+	let parts: Vec<&str> = uri.split(':').collect();
+
+	let valid = match parts.len() {
+		3 => ["album", "artist", "playlist"].contains(&parts[1]),
+		// legacy playlist URIs: spotify:user:<user>:playlist:<id>
+		5 => parts[1] == "user" && parts[3] == "playlist",
+		_ => false
+	};
+
+	valid && parts[0] == "spotify" && parts.iter().all(|part| !part.is_empty())
+}
+
+// the tracks of an album, artist or playlist we haven't handed over to the player yet. Large playlists come in pages -
+// only the IDs of the pages fetched so far are kept, the next page is only asked for once we're running out of tracks
+pub struct Context {
+	pub uri: String,
+	tracks: VecDeque<SpotifyId>,
+	next_page: Option<String>
+}
+
+impl Context {
+	pub fn len(&self) -> usize {
+		self.tracks.len()
+	}
+
+	pub fn has_more_pages(&self) -> bool {
+		self.next_page.is_some()
+	}
+
+	// where the track is among those we haven't handed over yet
+	pub fn index_of(&self, track: SpotifyId) -> Option<usize> {
+		self.tracks.iter().position(|id| *id == track)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.tracks.is_empty() && !self.has_more_pages()
+	}
+
+	// the next (up to) count tracks, in the order the Spotify applications would play them
+	pub fn take(&mut self, count: usize) -> Vec<SpotifyId> {
+		let count = cmp::min(count, self.tracks.len());
+		self.tracks.drain(..count).collect()
+	}
+
+	// the window of window_size tracks the index falls into - the tracks before it are dropped, those after it kept for the next window
+	pub fn window(&mut self, index: usize, window_size: usize) -> Result<Vec<SpotifyId>, &'static str> {
+		if self.len() <= index {
+			return Err(if index == 0 { "empty_context" } else { "invalid_start_index" });
+		}
+
+		self.take(index - index % window_size);
+		Ok(self.take(window_size))
+	}
+
+	fn add_page(&mut self, data: Option<&Vec<u8>>) {
+		let page = data.and_then(|data| serde_json::from_slice::<Value>(data).ok()).unwrap_or(Value::Null);
+
+		// the resolved context is a list of pages - a page fetched on its own looks like one of them
+		let pages = match page.get("pages").and_then(|pages| pages.as_array()) {
+			Some(pages) => pages.clone(),
+			None => vec![page.clone()]
+		};
+
+		self.next_page = None;
+
+		for page in &pages {
+			match page.get("tracks").and_then(|tracks| tracks.as_array()) {
+				Some(tracks) => {
+					self.tracks.extend(tracks.iter()
+						.filter_map(|track| track.get("uri").and_then(|uri| uri.as_str()))
+						.filter_map(|uri| SpotifyId::from_uri(uri).ok()));
+
+					self.next_page = page.get("next_page_url").and_then(|url| url.as_str()).map(|url| url.to_string());
+				}
+				// not resolved yet - that's where we'll continue
+				None => {
+					self.next_page = page.get("page_url").and_then(|url| url.as_str()).map(|url| url.to_string());
+					break;
+				}
+			}
+		}
+	}
+}
+
+// resolve an album, artist or playlist - large playlists only up to their first page
+pub fn context(session: &Session, context_uri: &str) -> Box<Future<Item=Context, Error=MercuryError>> {
+	let mut context = Context {
+		uri: context_uri.to_string(),
+		tracks: VecDeque::new(),
+		next_page: None
+	};
+
+	let context = session.mercury().get(format!("hm://context-resolve/v1/{}", context_uri))
+		.map(move |response| {
+			context.add_page(response.payload.first());
+			context
+		});
+
+	Box::new(context)
+}
+
+// add the context's next pages to the tracks still to play, until it has what we're looking for or there are no more
+pub fn more_until<P>(session: &Session, context: Context, done: P) -> Box<Future<Item=Context, Error=MercuryError>>
+	where P: FnMut(&Context) -> bool + 'static
+{
+	let session = session.clone();
+
+	pages_until(context, done, move |url| Box::new(session.mercury().get(url).map(|response| response.payload.into_iter().next())))
+}
+
+fn pages_until<P, F>(context: Context, mut done: P, mut fetch: F) -> Box<Future<Item=Context, Error=MercuryError>>
+	where P: FnMut(&Context) -> bool + 'static, F: FnMut(String) -> Box<Future<Item=Option<Vec<u8>>, Error=MercuryError>> + 'static
+{
+	Box::new(future::loop_fn(context, move |mut context| {
+		let url = if done(&context) { None } else { context.next_page.take() };
+
+		match url {
+			Some(url) => future::Either::A(fetch(url).map(move |page| {
+				context.add_page(page.as_ref());
+				future::Loop::Continue(context)
+			})),
+			None => future::Either::B(future::ok(future::Loop::Break(context)))
+		}
+	}))
+}
+
+// ask the target device to play the tracks, starting with the one at index, like a Spotify application would
+pub fn load(session: &Session, ident: String, target: String, context_uri: &str, tracks: &[SpotifyId], index: u32, position: u32) -> Box<Future<Item=(), Error=MercuryError>> {
+	let mut state = State::new();
+	state.set_context_uri(context_uri.to_string());
+	state.set_status(PlayStatus::kPlayStatusPlay);
+	state.set_playing_track_index(index);
+	state.set_position_ms(position);
+	state.set_position_measured_at(now_ms() as u64);
+	state.set_track(RepeatedField::from_vec(tracks.iter().map(|track| {
+		let mut track_ref = TrackRef::new();
+		track_ref.set_gid(track.to_raw().to_vec());
+		track_ref.set_uri(track.to_uri());
+		track_ref
+	}).collect()));
+
+	let mut load = frame(&ident, MessageType::kMessageTypeLoad);
+	load.set_recipient(RepeatedField::from_vec(vec![target]));
+	load.set_state(state);
+
+	Box::new(session.mercury().send(remote_uri(session), load.write_to_bytes().unwrap()).map(|_| ()))
+}
+
+// the position was measured when the state was sent - add what has been played since
+fn current_position(state: &State) -> u32 {
+	if state.get_status() == PlayStatus::kPlayStatusPlay {
+		position_at(state.get_position_ms(), state.get_position_measured_at(), now_ms() as u64)
+	}
+	else {
+		state.get_position_ms()
+	}
+}
+
+// measured_at comes from the other device's clock, which might be ahead of ours - or not set at all
+fn position_at(position: u32, measured_at: u64, now: u64) -> u32 {
+	if measured_at == 0 {
+		return position;
+	}
+
+	let elapsed = cmp::min(now.saturating_sub(measured_at), u32::max_value() as u64) as u32;
+	position.saturating_add(elapsed)
+}
+
+// what the active device is playing - or a plain status if nothing's going on
+pub fn state_json(devices: &[Frame]) -> Value {
+	let active = match devices.iter().find(|device| device.get_device_state().get_is_active()) {
+		Some(active) => active,
+		None => return json!({ "status": "no_active_playback" })
+	};
+
+	let state = active.get_state();
+
+	let status = match state.get_status() {
+		PlayStatus::kPlayStatusPlay => "playing",
+		PlayStatus::kPlayStatusPause => "paused",
+		PlayStatus::kPlayStatusLoading => "loading",
+		PlayStatus::kPlayStatusStop => "stopped"
+	};
+
+	let track = state.get_track().get(state.get_playing_track_index() as usize)
+		.and_then(|track| {
+			if !track.get_uri().is_empty() {
+				Some(track.get_uri().to_string())
+			}
+			else {
+				SpotifyId::from_raw(track.get_gid()).ok().map(|id| id.to_uri())
+			}
+		});
+
+	json!({
+		"status": status,
+		"device": device_json(active),
+		"context": state.get_context_uri(),
+		"track": track,
+		"position": current_position(state) as f64 / 1000.0,
+		"volume": active.get_device_state().get_volume(),
+		"shuffle": state.get_shuffle(),
+		"repeat": state.get_repeat()
+	})
+}
+
+pub fn device_json(frame: &Frame) -> Value {
+	let device_state = frame.get_device_state();
+
+	json!({
+		"id": frame.get_ident(),
+		"name": device_state.get_name(),
+		"type": device_type(device_state),
+		"active": device_state.get_is_active(),
+		"volume": device_state.get_volume()
+	})
+}
+
+fn device_type(device_state: &DeviceState) -> &'static str {
+	let device_type = device_state.get_capabilities().iter()
+		.find(|capability| capability.get_typ() == CapabilityType::kDeviceType)
+		.and_then(|capability| capability.get_intValue().first().cloned());
+
+	match device_type {
+		Some(1) => "Computer",
+		Some(2) => "Tablet",
+		Some(3) => "Smartphone",
+		Some(4) => "Speaker",
+		Some(5) => "TV",
+		Some(6) => "AVR",
+		Some(7) => "STB",
+		Some(8) => "AudioDongle",
+		_ => "Unknown"
+	}
+}
+
+// collects the notifications addressed to us until the timeout fires
+struct Notifications {
+	responses: UnboundedReceiver<MercuryResponse>,
+	timeout: Timeout,
+	ident: String,
+	frames: Vec<Frame>
+}
+
+impl Future for Notifications {
+	type Item = Vec<Frame>;
+	type Error = MercuryError;
+
+	fn poll(&mut self) -> Poll<Vec<Frame>, MercuryError> {
+		loop {
+			match self.responses.poll() {
+				Ok(Async::Ready(Some(response))) => {
+					for data in response.payload {
+						if let Ok(frame) = protobuf::parse_from_bytes::<Frame>(&data) {
+							let for_us = frame.get_recipient().is_empty() || frame.get_recipient().iter().any(|recipient| *recipient == self.ident);
+
+							if frame.get_typ() == MessageType::kMessageTypeNotify && frame.get_ident() != self.ident && for_us {
+								// only keep the latest notification of every device
+								self.frames.retain(|known| known.get_ident() != frame.get_ident());
+								self.frames.push(frame);
+							}
+						}
+					}
+				}
+				Ok(Async::NotReady) => break,
+				Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready(mem::replace(&mut self.frames, Vec::new())))
+			}
+		}
+
+		match self.timeout.poll() {
+			Ok(Async::NotReady) => Ok(Async::NotReady),
+			_ => Ok(Async::Ready(mem::replace(&mut self.frames, Vec::new())))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	// a playlist of 350 tracks, served in pages of 100
+	const PLAYLIST_SIZE: u128 = 350;
+	const PAGE_SIZE: u128 = 100;
+
+	fn track(id: u128) -> SpotifyId {
+		SpotifyId::from_base62(&format!("{:0>22}", id)).unwrap()
+	}
+
+	fn tracks_json(ids: &[u128]) -> Value {
+		Value::Array(ids.iter().map(|id| json!({ "uri": track(*id).to_uri() })).collect())
+	}
+
+	fn context(data: &Value) -> Context {
+		let mut context = Context {
+			uri: "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M".to_string(),
+			tracks: VecDeque::new(),
+			next_page: None
+		};
+
+		context.add_page(Some(&data.to_string().into_bytes()));
+		context
+	}
+
+	fn page_url(page: u128) -> String {
+		format!("hm://context-resolve/v1/page/{}", page)
+	}
+
+	fn page(page: u128) -> Value {
+		let first = page * PAGE_SIZE + 1;
+		let last = cmp::min(first + PAGE_SIZE, PLAYLIST_SIZE + 1);
+		let next_page = if last <= PLAYLIST_SIZE { Some(page_url(page + 1)) } else { None };
+
+		json!({ "tracks": tracks_json(&(first..last).collect::<Vec<_>>()), "next_page_url": next_page })
+	}
+
+	// the playlist's first page, and the pages fetched for it from then on
+	fn playlist() -> (Context, Box<FnMut(String) -> Box<Future<Item=Option<Vec<u8>>, Error=MercuryError>>>, Rc<RefCell<Vec<String>>>) {
+		let fetched = Rc::new(RefCell::new(Vec::new()));
+
+		let log = fetched.clone();
+		let fetch = move |url: String| {
+			let number = url.rsplit('/').next().and_then(|number| number.parse().ok()).unwrap();
+			log.borrow_mut().push(url);
+			Box::new(future::ok(Some(page(number).to_string().into_bytes()))) as Box<Future<Item=Option<Vec<u8>>, Error=MercuryError>>
+		};
+
+		(context(&page(0)), Box::new(fetch), fetched)
+	}
+
+	#[test]
+	fn resolved_pages_added_up_to_the_first_unresolved() {
+		let mut context = context(&json!({
+			"pages": [
+				{ "tracks": tracks_json(&[1, 2, 3]), "next_page_url": "hm://context-resolve/v1/page/2" },
+				{ "tracks": tracks_json(&[4]), "next_page_url": "hm://context-resolve/v1/page/3" },
+				{ "page_url": "hm://context-resolve/v1/page/3" },
+				{ "tracks": tracks_json(&[9]) }
+			]
+		}));
+
+		assert_eq!(context.len(), 4);
+		assert_eq!(context.next_page, Some("hm://context-resolve/v1/page/3".to_string()));
+
+		assert_eq!(context.take(2), vec![track(1), track(2)]);
+		assert_eq!(context.index_of(track(1)), None);
+		assert_eq!(context.index_of(track(4)), Some(1));
+
+		assert_eq!(context.take(10), vec![track(3), track(4)]);
+		assert!(context.take(1).is_empty());
+		assert!(!context.is_empty());
+	}
+
+	#[test]
+	fn position_measured_by_other_clock() {
+		let now = 1_500_000_000_000;
+
+		assert_eq!(position_at(10_000, now - 2_000, now), 12_000);
+		// a clock running ahead of ours, or no time at all, must not make up a position
+		assert_eq!(position_at(10_000, now + 5_000, now), 10_000);
+		assert_eq!(position_at(10_000, 0, now), 10_000);
+		assert_eq!(position_at(u32::max_value(), now - 2_000, now), u32::max_value());
+	}
+
+	#[test]
+	fn single_page_appended() {
+		let mut context = context(&json!({ "tracks": tracks_json(&[1, 2]), "next_page_url": "hm://context-resolve/v1/page/2" }));
+		assert!(context.has_more_pages());
+
+		context.add_page(Some(&json!({ "tracks": tracks_json(&[3]) }).to_string().into_bytes()));
+		assert!(!context.has_more_pages());
+		assert_eq!(context.take(3), vec![track(1), track(2), track(3)]);
+		assert!(context.is_empty());
+	}
+
+	#[test]
+	fn window_beyond_the_first_page() {
+		let (context, fetch, fetched) = playlist();
+
+		let mut context = pages_until(context, |context| context.len() >= 300, fetch).wait().unwrap();
+		// the last page isn't needed for the window
+		assert_eq!(*fetched.borrow(), vec![page_url(1), page_url(2)]);
+		assert!(context.has_more_pages());
+
+		let window = context.window(250, 100).unwrap();
+		assert_eq!(window.len(), 100);
+		assert_eq!(window[0], track(201));
+		assert_eq!(window[50], track(251));
+		assert_eq!(window[99], track(300));
+		assert_eq!(context.len(), 0);
+	}
+
+	#[test]
+	fn partial_last_window() {
+		let (context, fetch, _) = playlist();
+
+		let mut context = pages_until(context, |_| false, fetch).wait().unwrap();
+		assert_eq!(context.len() as u128, PLAYLIST_SIZE);
+		assert!(!context.has_more_pages());
+
+		let window = context.window(349, 100).unwrap();
+		assert_eq!(window.len(), 50);
+		assert_eq!(window[0], track(301));
+		assert_eq!(window[49], track(350));
+	}
+
+	#[test]
+	fn window_out_of_range() {
+		let mut empty = context(&json!({ "tracks": [] }));
+		assert_eq!(empty.window(0, 100), Err("empty_context"));
+
+		let (context, fetch, fetched) = playlist();
+
+		let mut context = pages_until(context, |context| context.len() >= 500, fetch).wait().unwrap();
+		assert_eq!(fetched.borrow().len(), 3);
+		assert_eq!(context.window(400, 100), Err("invalid_start_index"));
+		assert_eq!(context.len() as u128, PLAYLIST_SIZE);
+	}
+
+	#[test]
+	fn invalid_page_ignored() {
+		let mut context = context(&json!({ "tracks": tracks_json(&[1]) }));
+		context.add_page(Some(&b"<html>".to_vec()));
+		context.add_page(None);
+
+		assert_eq!(context.len(), 1);
+		assert!(!context.has_more_pages());
+	}
+}
@@ -0,0 +1,61 @@
+use std::io::{stderr, Write};
+use std::process::Command;
+use std::thread;
+
+use librespot::playback::player::PlayerEvent;
+
+#[derive(Clone)]
+pub struct EventScript {
+	path: String
+}
+
+impl EventScript {
+	pub fn new(path: String) -> EventScript {
+		EventScript {
+			path: path
+		}
+	}
+
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	// run the script with the event details in its environment - same variable names as librespot's --onevent
+	pub fn run(&self, event: &PlayerEvent) {
+		let mut env = vec![];
+
+		match *event {
+			PlayerEvent::Changed { old_track_id, new_track_id } => {
+				env.push(("PLAYER_EVENT", "change".to_string()));
+				env.push(("OLD_TRACK_ID", old_track_id.to_base62()));
+				env.push(("TRACK_ID", new_track_id.to_base62()));
+			}
+			PlayerEvent::Started { track_id } => {
+				env.push(("PLAYER_EVENT", "start".to_string()));
+				env.push(("TRACK_ID", track_id.to_base62()));
+			}
+			PlayerEvent::Stopped { track_id } => {
+				env.push(("PLAYER_EVENT", "stop".to_string()));
+				env.push(("TRACK_ID", track_id.to_base62()));
+			}
+			PlayerEvent::Volume { volume } => {
+				env.push(("PLAYER_EVENT", "volume".to_string()));
+				env.push(("VOLUME", volume.to_string()));
+			}
+			PlayerEvent::Seek { position } => {
+				env.push(("PLAYER_EVENT", "seek".to_string()));
+				env.push(("POSITION", position.to_string()));
+			}
+		}
+
+		match Command::new(&self.path).envs(env).spawn() {
+			Ok(mut child) => {
+				// don't block the reactor - reap the child in the background
+				thread::spawn(move || child.wait());
+			}
+			Err(e) => {
+				writeln!(stderr(), "error: failed to run event script '{}': {}", self.path, e).unwrap();
+			}
+		}
+	}
+}
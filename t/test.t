@@ -3,9 +3,11 @@
 use strict;
 
 use Data::Dump;
+use Digest::SHA qw(sha1_hex);
 use File::Slurp qw(read_file);
 use File::Spec::Functions qw(catdir catfile);
 use FindBin qw($Bin);
+use IO::Socket::INET;
 use JSON;
 use Test::More;
 
@@ -18,7 +20,7 @@ my $dataFile = catfile($Bin, 'test-data.json');
 
 mkdir $cacheDir;
 
-plan tests => 17;
+plan tests => 76;
 
 my $binary = catdir($baseDir, 'target/debug/spotty');
 
@@ -36,6 +38,209 @@ $binary = "RUST_BACKTRACE=full $binary -n 'Spotty testing'";
 {
 	my $checkData = `$binary --check`;
 	ok($checkData && $checkData =~ /ok spotty/, 'received response to quick check: ' . $checkData);
+
+	my $checkFile = catfile($cacheDir, 'check.json');
+	`$binary --check --check-output $checkFile`;
+	my $capabilities = eval { decode_json(read_file($checkFile)) };
+	ok($capabilities && $capabilities->{version}, 'capabilities written to file');
+
+	# capabilities are only advertised if the option or code implementing them exists
+	my @missing = grep { $_ ne 'version' && $_ ne 'debug' && !$capabilities->{$_} } keys %$capabilities;
+	ok(!@missing, 'every capability is implemented: ' . join(', ', @missing));
+	unlink $checkFile;
+}
+
+{
+	my $deviceId = `$binary --print-device-id`;
+	chomp $deviceId;
+	ok($deviceId eq sha1_hex('Spotty testing'), 'device ID derived from name: ' . $deviceId);
+}
+
+SKIP: {
+	skip 'binary was built with an embedded client ID', 2 if -s catfile($baseDir, 'src', 'client_id.txt');
+
+	foreach my $clientId ('', '   ') {
+		my $tokenData = decode_json(`$binary --get-token --client-id '$clientId'`);
+		ok($? >> 8 && $tokenData->{error} eq 'missing_client_id', "blank client ID '$clientId' rejected");
+	}
+}
+
+{
+	`$binary --disable-discovery --credentials-json '{"username":"spotty"}' 2>/dev/null`;
+	ok($? >> 8, 'incomplete inline credentials refused');
+
+	my $headlessData = decode_json(`$binary --disable-discovery`);
+	ok($? >> 8 && $headlessData->{error} eq 'missing_credentials', 'headless mode without credentials refused');
+
+	# stdout is reserved for the audio data - errors are reported on stderr
+	my $streamData = decode_json(`$binary --single-track ${\TESTTRACKID} 2>&1 >/dev/null`);
+	ok($? >> 8 && $streamData->{error} eq 'missing_credentials', 'single track without credentials refused');
+
+	my $probeData = decode_json(`$binary --probe-metadata-batch ${\TESTTRACKID},spotify:track:invalid`);
+	ok($? >> 8 && $probeData->{error} eq 'missing_credentials', 'metadata batch without credentials refused');
+}
+
+{
+	`$binary --single-track ${\TESTTRACKID} --repeat forever 2>/dev/null`;
+	ok($? >> 8, 'invalid repeat mode refused');
+
+	# there's no context to repeat in single track mode
+	`$binary --single-track ${\TESTTRACKID} --repeat context 2>/dev/null`;
+	ok($? >> 8, 'context repeat mode refused');
+
+	`$binary --prefetch ${\TESTTRACKID} 2>/dev/null`;
+	ok($? >> 8, 'prefetching without a cache folder refused');
+
+	`$binary --session-timeout 10 --disable-discovery 2>/dev/null`;
+	ok($? >> 8, 'session timeout without discovery refused');
+
+	`$binary --print-device-id --log-level chatty 2>/dev/null`;
+	ok($? >> 8, 'invalid log level refused');
+
+	`$binary --dump-config --open-files-limit none 2>/dev/null`;
+	ok($? >> 8, 'invalid open files limit refused');
+
+	`$binary --dump-config --metadata-cache-ttl 60 2>/dev/null`;
+	ok($? >> 8, 'metadata cache TTL without metadata cache refused');
+
+	`$binary --refresh-credentials 2>/dev/null`;
+	ok($? >> 8, 'refreshing credentials without a cache folder refused');
+
+	`$binary --dump-config --start-position 30 2>/dev/null`;
+	ok($? >> 8, 'start position without single track refused');
+
+	`$binary --dump-config --single-track ${\TESTTRACKID} --passthrough-chunk-size 4096 2>/dev/null`;
+	ok($? >> 8, 'chunk size without pass-through refused');
+
+	`$binary --dump-config --play-context spotify:track:${\TESTTRACKID} 2>/dev/null`;
+	ok($? >> 8, 'track refused as context to play');
+
+	`$binary --dump-config --start-index 3 2>/dev/null`;
+	ok($? >> 8, 'start index without context refused');
+
+	`$binary --dump-config -c $cacheDir/unmounted --cache-wait 1 2>/dev/null`;
+	ok($? >> 8 && !-e "$cacheDir/unmounted", 'unavailable cache folder given up on, and not created');
+
+	`$binary --dump-config --exit-on-idle never 2>/dev/null`;
+	ok($? >> 8, 'invalid idle time refused');
+
+	# nobody is going to pick us - we should be gone after a second
+	`$binary --exit-on-idle 1 2>/dev/null`;
+	ok(!($? >> 8), 'idle instance quit');
+
+	my $config = `$binary --dump-config --lms-auth c2VjcmV0 --client-id 0123456789abcdef`;
+	my $configData = decode_json($config);
+	ok($configData && $configData->{connect}->{name} eq 'Spotty testing', 'effective configuration dumped');
+	ok($config !~ /c2VjcmV0|0123456789abcdef/ && $configData->{lms}->{auth} eq '<redacted>', 'secrets redacted from configuration');
+
+	$configData = decode_json(`$binary --dump-config --metered --bitrate 320`);
+	ok($configData && $configData->{player}->{bitrate} eq 'Bitrate96', 'metered connection overrides bitrate: ' . $configData->{player}->{bitrate});
+
+	my @names = map { decode_json(`$binary --dump-config --force-discovery-name-unique -c $_`)->{connect}->{name} } ($cacheDir, catdir($cacheDir, 'other'), $cacheDir);
+	ok($names[0] =~ /^Spotty testing \([0-9a-f]{4}\)$/ && $names[0] ne $names[1] && $names[0] eq $names[2], 'instances with the same name told apart: ' . join(', ', @names));
+
+	$configData = decode_json(`$binary --dump-config --lms-retry-base-ms 100`);
+	ok($configData && $configData->{lms}->{retry_base_ms} == 100 && $configData->{lms}->{retry_multiplier} == 3, 'LMS retries configured');
+
+	$configData = decode_json(`$binary --dump-config --lms-retry-base-ms 100 --lms-retry-multiplier 2`);
+	ok($configData && $configData->{lms}->{retry_multiplier} == 2, 'LMS retry multiplier configured: ' . $configData->{lms}->{retry_multiplier});
+
+	`$binary --dump-config --lms-auth 'c2VjcmV0\r\nX-Injected: 1' 2>/dev/null`;
+	ok($? >> 8, 'LMS authentication with a line break refused');
+
+	$configData = decode_json(`$binary --dump-config --enable-volume-normalisation --normalisation-pregain -3.5`);
+	ok($configData && $configData->{player}->{normalisation_pregain} == -3.5, 'normalisation pregain configured: ' . $configData->{player}->{normalisation_pregain});
+
+	`$binary --dump-config --enable-volume-normalisation --normalisation-pregain 30 2>/dev/null`;
+	ok($? >> 8, 'out of range normalisation pregain refused');
+
+	$configData = decode_json(`$binary --dump-config --device-type TV`);
+	ok($configData && lc($configData->{connect}->{device_type}) eq 'tv', 'device type configured: ' . $configData->{connect}->{device_type});
+
+	`$binary --dump-config --device-type toaster 2>/dev/null`;
+	ok($? >> 8, 'unknown device type refused');
+
+	$configData = decode_json(`$binary --dump-config -c $cacheDir --resume-last 2>/dev/null`);
+	ok($configData && $configData->{resume_last}, 'resuming the last track configured');
+
+	`$binary --dump-config --resume-last 2>/dev/null`;
+	ok($? >> 8, 'resuming the last track without cache refused');
+
+	$configData = decode_json(`$binary --dump-config --connect-retry-on-auth-error`);
+	ok($configData && $configData->{session}->{connect_retry_on_auth_error}, 'retrying rejected credentials configured');
+}
+
+{
+	my $lmsData = decode_json(`$binary --test-lms --lms 127.0.0.1:1`);
+	ok($lmsData && $lmsData->{error} && $lmsData->{url} eq 'http://127.0.0.1:1/jsonrpc.js', 'unreachable LMS reported: ' . $lmsData->{error});
+
+	$lmsData = decode_json(`$binary --test-lms --lms lms.invalid:9000`);
+	ok($lmsData && $lmsData->{error} =~ /could not resolve host/, 'unknown LMS host reported: ' . $lmsData->{error});
+
+	my ($banner) = split /\n/, `$binary --startup-json --test-lms --lms 127.0.0.1:1`;
+	$banner = decode_json($banner);
+	ok($banner && $banner->{mode} eq 'test-lms' && $banner->{pid} && $banner->{capabilities}->{'startup-json'}, 'startup banner received: ' . $banner->{mode});
+
+	$lmsData = decode_json(`$binary --test-lms --lms 127.0.0.1:1 --player-mac 00-04-20-AB-CD-EF`);
+	ok($lmsData && $lmsData->{player} eq '00:04:20:ab:cd:ef', 'dash separated MAC address normalized: ' . $lmsData->{player});
+
+	$lmsData = decode_json(`$binary --test-lms --lms 127.0.0.1:1 --player-mac 00:04:20:12:34:56`);
+	ok($lmsData && $lmsData->{player} eq '00:04:20:12:34:56', 'colon separated MAC address unchanged: ' . $lmsData->{player});
+}
+
+{
+	# run the event pipeline without talking to Spotify
+	my $eventsFile = catfile($cacheDir, 'events.txt');
+	my $script = catfile($cacheDir, 'event.sh');
+	open my $fh, '>', $script;
+	print $fh "#!/bin/sh\necho \$PLAYER_EVENT >> $eventsFile\n";
+	close $fh;
+	chmod 0755, $script;
+	unlink $eventsFile;
+
+	my $audio = `$binary --fake-track $script --event-script $script`;
+	ok($audio eq read_file($script), 'fake track passed through to stdout');
+
+	sleep 1;
+	my $events = join(',', sort map { chomp; $_ } read_file($eventsFile));
+	ok($events eq 'change,start,stop', 'fake track events emitted: ' . $events);
+
+	unlink $eventsFile, $script;
+
+	my @printed = grep { /^event: / } split /\n/, `$binary --fake-track $0 --print-events 2>&1 >/dev/null`;
+	ok(scalar(grep { /^event: start: \w+ \(spotify:track:\w+\)$/ } @printed), 'player events printed: ' . join(' | ', @printed));
+
+	@printed = grep { /^event: / } split /\n/, `$binary --fake-track $0 --print-events --syslog 2>&1 >/dev/null`;
+	ok(!@printed, 'player events sent to syslog instead of stderr');
+}
+
+{
+	my ($start) = grep { $_->{params}->[1]->[1] eq 'start' } captureLMSRequests("--fake-track $0");
+	ok($start && $start->{params}->[1]->[2] =~ /^[0-9a-zA-Z]{22}$/, 'base62 track ID sent to LMS: ' . ($start ? $start->{params}->[1]->[2] : ''));
+
+	my $format = $start ? $start->{params}->[1]->[4] : {};
+	ok($format && $format->{format} eq 'pcm' && $format->{rate} == 44100, 'audio format sent to LMS: ' . ($format ? $format->{format} : ''));
+
+	($start) = grep { $_->{params}->[1]->[1] eq 'start' } captureLMSRequests("--fake-track $0 --event-uri-form uri");
+	ok($start && $start->{params}->[1]->[2] =~ /^spotify:track:[0-9a-zA-Z]{22}$/, 'track URI sent to LMS: ' . ($start ? $start->{params}->[1]->[2] : ''));
+
+	my @batches = captureLMSRequests("--fake-track $0 --lms-batch 200", '[{"id":1,"result":{}}]');
+	ok(@batches == 1 && ref $batches[0] eq 'ARRAY' && @{$batches[0]} >= 3, 'events sent to LMS in one batch: ' . join(',', map { ref $_ eq 'ARRAY' ? scalar @$_ : 1 } @batches));
+
+	my @requests = ref $batches[0] eq 'ARRAY' ? @{$batches[0]} : ();
+	my $id = 0;
+	ok(@requests && !grep({ $_->{method} ne 'slim.request' || $_->{params}->[0] ne '00:04:20:12:34:56' || $_->{id} != ++$id } @requests), 'JSON-RPC requests addressed to the player');
+
+	# an invalid MAC address is passed on as is - it must still end up in valid JSON
+	my $mac = 'a"b\\c';
+	@requests = eval { captureLMSRequests("--fake-track $0", undef, undef, $mac) };
+	ok(!$@ && @requests && !grep({ $_->{params}->[0] ne $mac } @requests), 'player MAC with quotes and backslashes escaped: ' . ($@ || scalar @requests));
+
+	# pretend we're a web server rather than LMS
+	captureLMSRequests("--fake-track $0", '<html><body>Hello</body></html>', 'text/html');
+	my $warnings = read_file(catfile($cacheDir, 'lms.err'));
+	ok($warnings =~ /didn't answer with JSON-RPC/, 'HTML response from LMS reported');
+	unlink catfile($cacheDir, 'lms.err');
 }
 
 {
@@ -62,6 +267,16 @@ if ($username && $password) {
 	ok($token && ref $token && $token->{accessToken}, "received accessToken");
 }
 
+SKIP: {
+	skip 'no client ID in test data', 1 unless $testData->{client_id};
+
+	# surrounding whitespace, as it might come from a file, must not end up in the request
+	my $tokenData = `$binary -c $cacheDir --get-token -i ' $testData->{client_id}
+'`;
+	my $token = decode_json($tokenData);
+	ok($token && ref $token && $token->{accessToken}, "received accessToken using padded client ID");
+}
+
 require Proc::Background;
 my $daemon;
 {
@@ -91,6 +306,39 @@ print "\nWe're now going to download some data. Please be patient...\n";
 	$daemon->die if $daemon->alive;
 }
 
+{
+	my $headless = Proc::Background->new("$binary -c $cacheDir --disable-discovery --disable-audio-cache");
+	sleep 5;
+	ok($headless->alive, "headless daemon connected and running");
+	$headless->die if $headless->alive;
+}
+
+{
+	my $port = 24879;
+	`$binary --health-port $port --health-address nowhere 2>/dev/null`;
+	ok($? >> 8, 'invalid health address refused');
+
+	my $monitored = Proc::Background->new("$binary --health-port $port");
+	sleep 1;
+
+	my $get = sub {
+		my ($path) = @_;
+
+		my $socket = IO::Socket::INET->new(PeerAddr => "127.0.0.1:$port") || return;
+		print $socket "GET $path HTTP/1.0\r\n\r\n";
+		my $response = join('', <$socket>);
+		my ($body) = $response =~ /\r\n\r\n(.*)$/s;
+		return eval { decode_json($body) };
+	};
+
+	my $health = $get->('/health');
+	ok($health && defined $health->{uptime} && !$health->{connected}, 'health endpoint reports state of unconnected daemon');
+
+	my $state = $get->('/state');
+	ok($state && $state->{error} eq 'not_connected', 'state endpoint reports unconnected daemon');
+	$monitored->die if $monitored->alive;
+}
+
 
 sub testCredentials {
 	my ($username, $password) = @_;
@@ -112,6 +360,43 @@ sub testCredentials {
 	ok($credentials && $credentials->{auth_data}, "credentials file is readable and valid: " . ($credentials && $credentials->{auth_data} ? $credentials->{username} : 'unknown'));
 }
 
+# run spotty against a minimal fake LMS, and return the JSON-RPC requests it received
+sub captureLMSRequests {
+	my ($args, $response, $contentType, $playerMac) = @_;
+
+	$response ||= '{"id":1,"result":{}}';
+	$contentType ||= 'application/json';
+	$playerMac ||= '00:04:20:12:34:56';
+
+	my $server = IO::Socket::INET->new(Listen => 5, LocalAddr => '127.0.0.1', LocalPort => 0, ReuseAddr => 1) || return;
+	$server->timeout(3);
+	my $port = $server->sockport;
+
+	my $pid = fork();
+	if (!$pid) {
+		exec("$binary $args --lms 127.0.0.1:$port --player-mac '$playerMac' > /dev/null 2> " . catfile($cacheDir, 'lms.err'));
+	}
+
+	my @requests;
+	while (my $client = $server->accept) {
+		my ($header, $body) = ('', '');
+		while (my $line = <$client>) {
+			last if $line eq "\r\n";
+			$header .= $line;
+		}
+
+		my ($length) = $header =~ /Content-Length:\s*(\d+)/i;
+		read($client, $body, $length) if $length;
+		push @requests, decode_json($body) if $body;
+
+		printf $client "HTTP/1.1 200 OK\r\nContent-Type: %s\r\nContent-Length: %d\r\nConnection: close\r\n\r\n%s", $contentType, length($response), $response;
+		close $client;
+	}
+
+	waitpid($pid, 0);
+	return @requests;
+}
+
 sub readCredentials {
 	my $file = shift || $credsFile;
 